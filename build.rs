@@ -1,4 +1,5 @@
 use std::env;
+use std::iter;
 use std::process::Command;
 use std::str;
 
@@ -6,26 +7,130 @@ use std::str;
 // opening a GitHub issue if your build environment requires some way to enable
 // these cfgs other than by executing our build script.
 fn main() {
-    let minor = match rustc_minor_version() {
-        Some(minor) => minor,
-        None => return,
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=PROC_MACRO_HACK_MODE");
+    println!("cargo:rerun-if-env-changed=PROC_MACRO_HACK_DEBUG");
+    println!("cargo:rerun-if-env-changed=RUSTC_WRAPPER");
+    println!("cargo:rerun-if-env-changed=RUSTC_WORKSPACE_WRAPPER");
+
+    let rustc = rustc_version();
+
+    // An undetectable compiler is assumed to be a new one.
+    let check_cfg = match rustc {
+        Ok(ref rustc) => rustc.minor >= 80,
+        Err(_) => true,
+    };
+    if check_cfg {
+        println!("cargo:rustc-check-cfg=cfg(need_proc_macro_hack)");
+        println!("cargo:rustc-check-cfg=cfg(no_span_locations)");
+    }
+
+    // Span::file, line, column and end stabilized in Rust 1.88.
+    if let Ok(ref rustc) = rustc {
+        if rustc.minor < 88 {
+            println!("cargo:rustc-cfg=no_span_locations");
+        }
+    }
+
+    let need_proc_macro_hack = match env::var("PROC_MACRO_HACK_MODE") {
+        Ok(ref mode) if mode == "hack" => true,
+        Ok(ref mode) if mode == "native" => false,
+        Ok(ref mode) if mode == "auto" => need_proc_macro_hack(rustc),
+        Ok(mode) => {
+            println!(
+                "cargo:warning=ignoring unrecognized PROC_MACRO_HACK_MODE={:?}; expected one of: hack, native, auto",
+                mode,
+            );
+            need_proc_macro_hack(rustc)
+        }
+        Err(_) => need_proc_macro_hack(rustc),
     };
 
-    // Function-like procedural macros in expressions, patterns, and statements
-    // stabilized in Rust 1.45:
-    // https://blog.rust-lang.org/2020/07/16/Rust-1.45.0.html#stabilizing-function-like-procedural-macros-in-expressions-patterns-and-statements
-    if minor < 45 {
+    if need_proc_macro_hack {
         println!("cargo:rustc-cfg=need_proc_macro_hack");
     }
 }
 
-fn rustc_minor_version() -> Option<u32> {
-    let rustc = env::var_os("RUSTC")?;
-    let output = Command::new(rustc).arg("--version").output().ok()?;
-    let version = str::from_utf8(&output.stdout).ok()?;
-    let mut pieces = version.split('.');
-    if pieces.next() != Some("rustc 1") {
+// Function-like procedural macros in expressions, patterns, and statements
+// stabilized in Rust 1.45:
+// https://blog.rust-lang.org/2020/07/16/Rust-1.45.0.html#stabilizing-function-like-procedural-macros-in-expressions-patterns-and-statements
+fn need_proc_macro_hack(rustc: Result<RustcVersion, String>) -> bool {
+    match rustc {
+        // Only part of the 1.45 nightly cycle had the stabilization.
+        Ok(rustc) => match rustc.channel {
+            Channel::Stable | Channel::Beta => rustc.minor < 45,
+            Channel::Nightly | Channel::Dev => rustc.minor <= 45,
+        },
+        Err(reason) => {
+            println!(
+                "cargo:warning={}; assuming a compiler with native support for proc macros in expression position. Set PROC_MACRO_HACK_MODE=hack or PROC_MACRO_HACK_MODE=native to choose explicitly.",
+                reason,
+            );
+            false
+        }
+    }
+}
+
+struct RustcVersion {
+    minor: u32,
+    channel: Channel,
+}
+
+enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+    Dev,
+}
+
+fn rustc_version() -> Result<RustcVersion, String> {
+    let rustc = env::var_os("RUSTC").ok_or("RUSTC is not set")?;
+
+    // Invoke rustc the same way Cargo does, through any configured wrappers.
+    let wrapper = env::var_os("RUSTC_WRAPPER").filter(|wrapper| !wrapper.is_empty());
+    let workspace_wrapper =
+        env::var_os("RUSTC_WORKSPACE_WRAPPER").filter(|wrapper| !wrapper.is_empty());
+    let mut program = wrapper
+        .into_iter()
+        .chain(workspace_wrapper)
+        .chain(iter::once(rustc));
+    let mut command = Command::new(program.next().unwrap());
+    // Plain --version would be answered by clippy-driver with its own version.
+    command.args(program).arg("-vV");
+    let description = format!("{:?}", command);
+
+    let output = command
+        .output()
+        .map_err(|err| format!("failed to run {}: {}", description, err))?;
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", description, output.status));
+    }
+    let version = str::from_utf8(&output.stdout)
+        .map_err(|_| format!("{} printed non-UTF-8 output", description))?;
+    parse_version(version)
+        .ok_or_else(|| format!("failed to parse output of {}: {:?}", description, version))
+}
+
+// release: 1.75.0
+// release: 1.77.0-beta.1
+// release: 1.78.0-nightly
+// release: 1.79.0-dev
+fn parse_version(version: &str) -> Option<RustcVersion> {
+    let release = version.lines().find(|line| line.starts_with("release: "))?;
+    let version = release["release: ".len()..].trim();
+    let mut pieces = version.splitn(2, '-');
+    let number = pieces.next()?;
+    let channel = match pieces.next() {
+        None => Channel::Stable,
+        Some(channel) if channel.starts_with("beta") => Channel::Beta,
+        Some(channel) if channel.starts_with("nightly") => Channel::Nightly,
+        Some(channel) if channel.starts_with("dev") => Channel::Dev,
+        Some(_) => return None,
+    };
+    let mut number = number.split('.');
+    if number.next()? != "1" {
         return None;
     }
-    pieces.next()?.parse().ok()
+    let minor = number.next()?.parse().ok()?;
+    Some(RustcVersion { minor, channel })
 }
@@ -0,0 +1,186 @@
+// Compile-time benchmark of dispatch! and dispatch_calls! against the
+// token-at-a-time muncher they replaced. Each workload is expanded by rustc
+// once with each macro, at the default recursion limit, and the wall time of
+// the compilation is reported. Workloads that exceed the recursion limit are
+// reported as such.
+//
+//     cargo bench -p proc-macro-nested
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const COUNT: &str = include_str!(concat!(env!("OUT_DIR"), env!("PATH_SEPARATOR"), "count.rs"));
+
+const DISPATCH: &str = include_str!(concat!(
+    env!("OUT_DIR"),
+    env!("PATH_SEPARATOR"),
+    "dispatch.rs"
+));
+
+const OLD_DISPATCH: &str = r"
+#[doc(hidden)]
+#[macro_export]
+macro_rules! dispatch {
+    (() $($bang:tt)*) => {
+        $crate::count!($($bang)*)
+    };
+    ((($($first:tt)*) $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($first)* $($rest)*) $($bang)*)
+    };
+    (([$($first:tt)*] $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($first)* $($rest)*) $($bang)*)
+    };
+    (({$($first:tt)*} $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($first)* $($rest)*) $($bang)*)
+    };
+    ((! $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($rest)*) $($bang)* !)
+    };
+    ((!= $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($rest)*) $($bang)* !)
+    };
+    (($first:tt $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($rest)*) $($bang)*)
+    };
+}
+";
+
+struct Workload {
+    name: &'static str,
+    input: String,
+    calls: usize,
+}
+
+fn workloads() -> Vec<Workload> {
+    let flat = |tokens: usize| {
+        let mut flat = String::new();
+        for i in 0..tokens / 2 {
+            write!(flat, "x{} + ", i).unwrap();
+        }
+        flat + "0"
+    };
+
+    let mut nested = String::from("0");
+    for _ in 0..16 {
+        nested = format!("m!({})", nested);
+    }
+
+    let mut mixed = String::new();
+    for i in 0..20 {
+        write!(mixed, "a{0} = m!([b{0}, c{0}] {{ d{0} != e{0} }}); ", i).unwrap();
+    }
+
+    vec![
+        Workload {
+            name: "flat, 100 tokens",
+            input: flat(100),
+            calls: 20,
+        },
+        Workload {
+            name: "flat, 500 tokens",
+            input: flat(500),
+            calls: 20,
+        },
+        Workload {
+            name: "flat, 900 tokens",
+            input: flat(900),
+            calls: 20,
+        },
+        Workload {
+            name: "flat, 1000 tokens",
+            input: flat(1000),
+            calls: 20,
+        },
+        Workload {
+            name: "16 nested calls",
+            input: nested,
+            calls: 200,
+        },
+        Workload {
+            name: "20 calls with groups",
+            input: mixed,
+            calls: 50,
+        },
+    ]
+}
+
+fn source(dispatch: &str, name: &str, workload: &Workload) -> String {
+    let mut source = String::from(COUNT);
+    source += dispatch;
+    for i in 0..=64 {
+        writeln!(
+            source,
+            "macro_rules! proc_macro_call_{0} {{ () => {{ {0} }}; }}",
+            i
+        )
+        .unwrap();
+    }
+    for i in 0..workload.calls {
+        writeln!(
+            source,
+            "pub fn f{}() -> usize {{ {}!(({})) }}",
+            i, name, workload.input,
+        )
+        .unwrap();
+    }
+    source
+}
+
+// None if the expansion exceeds the recursion limit.
+fn compile(source: &str) -> Option<Duration> {
+    let dir = env::temp_dir().join("proc-macro-nested-bench");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("lib.rs");
+    fs::write(&path, source).unwrap();
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let start = Instant::now();
+    let status = Command::new(rustc)
+        .arg("--edition=2018")
+        .arg("--crate-type=lib")
+        .arg("--emit=metadata")
+        .arg("--cap-lints=allow")
+        .arg("--out-dir")
+        .arg(&dir)
+        .arg(&path)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    let elapsed = start.elapsed();
+    if status.success() {
+        Some(elapsed)
+    } else {
+        None
+    }
+}
+
+fn millis(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => {
+            (duration.as_secs() * 1000 + u64::from(duration.subsec_millis())).to_string()
+        }
+        None => "limit".to_owned(),
+    }
+}
+
+fn main() {
+    println!(
+        "{:<24}{:>12}{:>12}{:>12}",
+        "workload", "old (ms)", "new (ms)", "calls (ms)",
+    );
+    for workload in workloads() {
+        let old = compile(&source(OLD_DISPATCH, "dispatch", &workload));
+        let new = compile(&source(DISPATCH, "dispatch", &workload));
+        let calls = compile(&source(DISPATCH, "dispatch_calls", &workload));
+        println!(
+            "{:<24}{:>12}{:>12}{:>12}",
+            workload.name,
+            millis(old),
+            millis(new),
+            millis(calls),
+        );
+    }
+}
@@ -1,41 +1,256 @@
 use std::env;
+use std::fmt::Write;
 use std::fs;
-use std::iter;
 use std::path::{self, Path};
 
 /*
 #[doc(hidden)]
 #[macro_export]
 macro_rules! count {
-    () => { proc_macro_call_0!() };
-    (!) => { proc_macro_call_1!() };
-    (!!) => { proc_macro_call_2!() };
+    // Ten more invocations: increment the tens digit, carrying as needed.
+    (@count [$d0:tt 0 $d2:tt] ! ! ! ! ! ! ! ! ! ! $($rest:tt)*) => {
+        $crate::count!(@count [$d0 1 $d2] $($rest)*)
+    };
     ...
+    // One more invocation: increment the ones digit, carrying as needed.
+    (@count [0 $d1:tt $d2:tt] ! $($rest:tt)*) => {
+        $crate::count!(@count [1 $d1 $d2] $($rest)*)
+    };
+    ...
+    (@count [0 0 0]) => { proc_macro_call_0!() };
+    (@count [1 0 0]) => { proc_macro_call_1!() };
+    ...
+    (@count $($rest:tt)*) => {
+        compile_error! {
+            "this macro does not support >64 nested macro invocations"
+        }
+    };
+    ($($bang:tt)*) => {
+        $crate::count!(@count [0 0 0] $($bang)*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! dispatch {
+    // The first `!` or group among the next 8 tokens, found by trying each
+    // position in order.
+    ((! ($($group:tt)*) $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($group)* $($rest)*) $($bang)* !)
+    };
+    ...
+    ((! $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($rest)*) $($bang)* !)
+    };
+    ...
+    (($t0:tt ($($group:tt)*) $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($group)* $($rest)*) $($bang)*)
+    };
+    ...
+    // None of the next 8 tokens is interesting.
+    (($t0:tt $t1:tt ... $t7:tt $($rest:tt)*) $($bang:tt)*) => {
+        $crate::dispatch!(($($rest)*) $($bang)*)
+    };
+    (($($rest:tt)*) $($bang:tt)*) => {
+        $crate::count!($($bang)*)
+    };
 }
+
+// dispatch_calls! is the same except that it counts only `name!(...)` macro
+// calls rather than every `!`.
 */
 
+const LIMIT_VAR: &str = "PROC_MACRO_NESTED_LIMIT";
+const DEFAULT_LIMIT: usize = 64;
+
+// Number of tokens that dispatch! steps over per recursive call when none of
+// them is a `!` or a group. Every position needs its own arms, so a larger
+// chunk makes each step slower to match. Beyond 8, short invocations compile
+// more slowly than with the token-at-a-time muncher (see benches/dispatch.rs).
+const DISPATCH_CHUNK: usize = 8;
+
 fn main() {
     // Tell Cargo not to rerun on src/lib.rs changes.
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed={}", LIMIT_VAR);
+
+    let limit = match env::var(LIMIT_VAR) {
+        Ok(limit) => limit
+            .trim()
+            .parse::<usize>()
+            .unwrap_or_else(|_| panic!("{} must be a number, found {:?}", LIMIT_VAR, limit)),
+        Err(_) => DEFAULT_LIMIT,
+    };
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+    write_if_changed(&out_dir.join("count.rs"), &count(limit));
+    write_if_changed(
+        &out_dir.join("dispatch.rs"),
+        &(dispatch() + &dispatch_calls()),
+    );
+
+    println!("cargo:rustc-env=PATH_SEPARATOR={}", path::MAIN_SEPARATOR);
+}
+
+fn count(limit: usize) -> String {
+    // The count is kept as little-endian decimal digits so that the size of
+    // the generated macro grows with the limit rather than with its square.
+    let digits = limit.to_string().len();
 
     let mut content = String::new();
     content += "#[doc(hidden)]\n";
     content += "#[macro_export]\n";
     content += "macro_rules! count {\n";
-    for i in 0..=64 {
-        let bangs = iter::repeat("!").take(i).collect::<String>();
-        content += &format!("    ({}) => {{ proc_macro_call_{}!() }};\n", bangs, i);
+    if digits > 1 {
+        increment(&mut content, digits, 1);
+    }
+    increment(&mut content, digits, 0);
+    for i in 0..=limit {
+        writeln!(
+            content,
+            "    (@count [{}]) => {{ proc_macro_call_{}!() }};",
+            digit_list(i, digits),
+            i,
+        )
+        .unwrap();
     }
-    content += "    ($(!)+) => {\n";
+    content += "    (@count $($rest:tt)*) => {\n";
     content += "        compile_error! {\n";
-    content += "            \"this macro does not support >64 nested macro invocations\"\n";
+    writeln!(
+        content,
+        "            \"this macro does not support >{} nested macro invocations; the limit can be raised by setting {} when building proc-macro-nested\"",
+        limit, LIMIT_VAR,
+    )
+    .unwrap();
     content += "        }\n";
     content += "    };\n";
+    content += "    ($($bang:tt)*) => {\n";
+    writeln!(
+        content,
+        "        $crate::count!(@count [{}] $($bang)*)",
+        digit_list(0, digits),
+    )
+    .unwrap();
+    content += "    };\n";
     content += "}\n";
 
+    content
+}
+
+fn dispatch() -> String {
+    let mut content = String::new();
+    content += "#[doc(hidden)]\n";
+    content += "#[macro_export]\n";
+    content += "macro_rules! dispatch {\n";
+    for position in 0..DISPATCH_CHUNK {
+        let skip = skipped_tokens(position);
+        // A macro call, counted and flattened in a single step.
+        for group in GROUPS {
+            content += &rule(
+                "dispatch",
+                &format!("{}! {}", skip, group),
+                "$($group)* $($rest)*",
+                true,
+            );
+        }
+        for bang in &["!", "!="] {
+            content += &rule("dispatch", &format!("{}{}", skip, bang), "$($rest)*", true);
+        }
+        for group in GROUPS {
+            content += &rule(
+                "dispatch",
+                &format!("{}{}", skip, group),
+                "$($group)* $($rest)*",
+                false,
+            );
+        }
+    }
+    content += &end("dispatch");
+    content
+}
+
+// Like dispatch! but counts only `name!(...)`, `name![...]` and `name!{...}`,
+// so that negations and `!=` do not count as nested invocations.
+fn dispatch_calls() -> String {
+    let mut content = String::new();
+    content += "#[doc(hidden)]\n";
+    content += "#[macro_export]\n";
+    content += "macro_rules! dispatch_calls {\n";
+    for position in 0..DISPATCH_CHUNK {
+        let skip = skipped_tokens(position);
+        for keyword in KEYWORDS {
+            content += &rule(
+                "dispatch_calls",
+                &format!("{}{} !", skip, keyword),
+                "$($rest)*",
+                false,
+            );
+        }
+        for group in GROUPS {
+            content += &rule(
+                "dispatch_calls",
+                &format!("{}$name:ident ! {}", skip, group),
+                "$($group)* $($rest)*",
+                true,
+            );
+        }
+        for group in GROUPS {
+            content += &rule(
+                "dispatch_calls",
+                &format!("{}{}", skip, group),
+                "$($group)* $($rest)*",
+                false,
+            );
+        }
+    }
+    content += &end("dispatch_calls");
+    content
+}
+
+const GROUPS: &[&str] = &["($($group:tt)*)", "[$($group:tt)*]", "{$($group:tt)*}"];
+
+// Keywords that may be directly followed by a unary `!` expression.
+const KEYWORDS: &[&str] = &[
+    "box", "break", "if", "in", "match", "return", "while", "yield",
+];
+
+fn rule(name: &str, pattern: &str, rest: &str, bang: bool) -> String {
+    format!(
+        "    (({} $($rest:tt)*) $($bang:tt)*) => {{\n        $crate::{}!(({}) $($bang)*{})\n    }};\n",
+        pattern,
+        name,
+        rest,
+        if bang { " !" } else { "" },
+    )
+}
+
+// Step over a chunk of uninteresting tokens, or count the bangs once the input
+// is exhausted.
+fn end(name: &str) -> String {
+    let mut content = rule(
+        name,
+        skipped_tokens(DISPATCH_CHUNK).trim_end(),
+        "$($rest)*",
+        false,
+    );
+    content += "    (($($rest:tt)*) $($bang:tt)*) => {\n";
+    content += "        $crate::count!($($bang)*)\n";
+    content += "    };\n";
+    content += "}\n";
+    content
+}
+
+fn skipped_tokens(n: usize) -> String {
+    let mut tokens = String::new();
+    for i in 0..n {
+        write!(tokens, "$t{}:tt ", i).unwrap();
+    }
+    tokens
+}
+
+fn write_if_changed(dest_path: &Path, content: &str) {
     let content = content.as_bytes();
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let ref dest_path = Path::new(&out_dir).join("count.rs");
 
     // Avoid bumping filetime if content is up to date. Possibly related to
     // https://github.com/dtolnay/proc-macro-hack/issues/56 ...?
@@ -45,6 +260,45 @@ fn main() {
     {
         fs::write(dest_path, content).unwrap();
     }
+}
 
-    println!("cargo:rustc-env=PATH_SEPARATOR={}", path::MAIN_SEPARATOR);
+// Arms consuming 10^position bangs at once by incrementing the digit at that
+// position. Every lower digit is passed through unchanged and every higher
+// digit that is a 9 carries into the next one.
+fn increment(content: &mut String, digits: usize, position: usize) {
+    let bangs = vec!["!"; (0..position).fold(1, |n, _| n * 10)].join(" ");
+    for carry in 0..digits - position {
+        for digit in 0..9 {
+            let mut pattern = Vec::new();
+            let mut replacement = Vec::new();
+            for i in 0..digits {
+                if i < position || i > position + carry {
+                    pattern.push(format!("$d{}:tt", i));
+                    replacement.push(format!("$d{}", i));
+                } else if i < position + carry {
+                    pattern.push("9".to_owned());
+                    replacement.push("0".to_owned());
+                } else {
+                    pattern.push(digit.to_string());
+                    replacement.push((digit + 1).to_string());
+                }
+            }
+            writeln!(
+                content,
+                "    (@count [{}] {} $($rest:tt)*) => {{\n        $crate::count!(@count [{}] $($rest)*)\n    }};",
+                pattern.join(" "),
+                bangs,
+                replacement.join(" "),
+            ).unwrap();
+        }
+    }
+}
+
+fn digit_list(mut n: usize, digits: usize) -> String {
+    let mut list = Vec::new();
+    for _ in 0..digits {
+        list.push((n % 10).to_string());
+        n /= 10;
+    }
+    list.join(" ")
 }
@@ -9,7 +9,8 @@
 //!
 //! To make a macro callable recursively, add a dependency on this crate from
 //! your declaration crate and update the `#[proc_macro_hack]` re-export as
-//! follows.
+//! follows. Current versions of proc-macro-hack expand such macros through
+//! `dispatch_calls!`, which requires proc-macro-nested 0.1.8 or newer.
 //!
 //! ```
 //! // Before
@@ -30,40 +31,51 @@
 //! No change is required within your definition crate, only to the re-export in
 //! the declaration crate.
 //!
+//! Nested invocations are recognized by their shape, `name!(...)`, `name![...]`
+//! or `name!{...}`. Other uses of `!` such as negation or `!=` do not count
+//! toward the limit below.
+//!
+//! If the macro's own output contains calls to other macros that support
+//! nested invocations, and those calls were not already part of its input,
+//! declare how many such calls it emits. Calls to ordinary macros like
+//! `panic!` or `vec!` in the output need no declaration.
+//!
+//! ```
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack(support_nested, internal_macro_calls = 2)]
+//! pub use demo_hack_impl::add_one;
+//! # };
+//! ```
+//!
+//! This number can't be inferred from the output. The declaration crate has to
+//! pick the name of the macro that hands back the output before the output
+//! exists, and the compiler rejects that name if one of the calls inside the
+//! output defines the same name.
+//!
 //! # Limitations
 //!
-//! - Nested invocations are preprocessed by a TT-muncher, so the caller's crate
-//!   will be required to contain `#![recursion_limit = "..."]` if there are
-//!   lengthy macro invocations.
+//! - Nested invocations are preprocessed by a TT-muncher which steps over up
+//!   to 8 tokens at a time and takes one more step for every `!` and every
+//!   group. Its recursion depth still grows linearly with the length of the
+//!   invocation, so only invocations up to about 900 tokens fit within the
+//!   default recursion limit. Longer invocations, or calls nested many levels
+//!   deep, require the caller's crate to contain `#![recursion_limit = "..."]`.
 //!
-//! - Only up to 64 nested invocations are supported.
+//! - Nested invocations inside a fragment that a `macro_rules!` macro passes
+//!   along, such as `$e:expr`, are hidden from the preprocessing, which sees
+//!   the fragment as a single token. Their numbering then collides with that
+//!   of the invocation around them.
+//!
+//! - Only up to 64 nested invocations are supported by default. A higher limit
+//!   can be set through the `PROC_MACRO_NESTED_LIMIT` environment variable at
+//!   the time proc-macro-nested is built, for example
+//!   `PROC_MACRO_NESTED_LIMIT=1024 cargo build`.
 
 #![no_std]
 
 include!(concat!(env!("OUT_DIR"), env!("PATH_SEPARATOR"), "count.rs"));
-
-#[doc(hidden)]
-#[macro_export]
-macro_rules! dispatch {
-    (() $($bang:tt)*) => {
-        $crate::count!($($bang)*)
-    };
-    ((($($first:tt)*) $($rest:tt)*) $($bang:tt)*) => {
-        $crate::dispatch!(($($first)* $($rest)*) $($bang)*)
-    };
-    (([$($first:tt)*] $($rest:tt)*) $($bang:tt)*) => {
-        $crate::dispatch!(($($first)* $($rest)*) $($bang)*)
-    };
-    (({$($first:tt)*} $($rest:tt)*) $($bang:tt)*) => {
-        $crate::dispatch!(($($first)* $($rest)*) $($bang)*)
-    };
-    ((! $($rest:tt)*) $($bang:tt)*) => {
-        $crate::dispatch!(($($rest)*) $($bang)* !)
-    };
-    ((!= $($rest:tt)*) $($bang:tt)*) => {
-        $crate::dispatch!(($($rest)*) $($bang)* !)
-    };
-    (($first:tt $($rest:tt)*) $($bang:tt)*) => {
-        $crate::dispatch!(($($rest)*) $($bang)*)
-    };
-}
+include!(concat!(
+    env!("OUT_DIR"),
+    env!("PATH_SEPARATOR"),
+    "dispatch.rs"
+));
@@ -0,0 +1,66 @@
+#[macro_use]
+extern crate proc_macro_nested;
+
+macro_rules! proc_macro_call_0 {
+    () => {
+        0
+    };
+}
+
+macro_rules! proc_macro_call_1 {
+    () => {
+        1
+    };
+}
+
+macro_rules! proc_macro_call_2 {
+    () => {
+        2
+    };
+}
+
+macro_rules! proc_macro_call_3 {
+    () => {
+        3
+    };
+}
+
+#[test]
+fn test_calls() {
+    assert_eq!(dispatch_calls!((m!(x))), 1);
+    assert_eq!(dispatch_calls!((m![x] + m! {x})), 2);
+    assert_eq!(dispatch_calls!((a::b::m!(m!(x)))), 2);
+    assert_eq!(dispatch_calls!(([{ (m!(x)) }])), 1);
+}
+
+#[test]
+fn test_negation() {
+    assert_eq!(dispatch_calls!((!x)), 0);
+    assert_eq!(dispatch_calls!((!(x))), 0);
+    assert_eq!(dispatch_calls!((!!x && !m!(x))), 1);
+    assert_eq!(dispatch_calls!((if !(x) { !y } else { m!(z) })), 1);
+    assert_eq!(
+        dispatch_calls!((match !(x) {} while !(y) {} return !(z))),
+        0
+    );
+}
+
+#[test]
+fn test_not_equal() {
+    assert_eq!(dispatch_calls!((x != y)), 0);
+    assert_eq!(dispatch_calls!((x != (y))), 0);
+    assert_eq!(dispatch_calls!((m!(x) != m!(y))), 2);
+}
+
+#[test]
+fn test_chunks() {
+    // Calls on either side of the first chunk of 32 tokens.
+    assert_eq!(
+        dispatch_calls!((
+            m!(0) a a a a a a a a a a a a a a a a a a a a a a a a a a a a a
+            m!(1) a a a a a a a a a a a a a a a a a a a a a a a a a a a a a
+            m!(2)
+        )),
+        3
+    );
+}
@@ -0,0 +1,128 @@
+// Dumps of each stage of the expansion, written when PROC_MACRO_HACK_DEBUG
+// names a directory.
+//
+// expand_define also pastes the part of this file below the marker into the
+// helpers of every implementation crate built while the variable is set. That
+// part therefore refers to std only by absolute path, and to proc_macro only
+// through the names imported here.
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+pub fn debug_enabled() -> bool {
+    debug_dir().is_some()
+}
+
+// ---- pasted into implementation crates from here on ----
+
+fn debug_dir() -> Option<::std::path::PathBuf> {
+    match ::std::env::var_os("PROC_MACRO_HACK_DEBUG") {
+        Some(ref dir) if !dir.is_empty() => Some(::std::path::PathBuf::from(dir)),
+        _ => None,
+    }
+}
+
+pub fn debug_dump(key: &str, stage: &str, tokens: &TokenStream) {
+    let dir = match debug_dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    // Set by Cargo for the crate that is being compiled. Without it, dumps
+    // from different crates could overwrite each other.
+    let krate = ::std::env::var("CARGO_CRATE_NAME").unwrap_or_else(|_| "unknown".to_owned());
+    let path = dir.join(format!("{}.{}.{}.rs", krate, key, stage));
+
+    // A failure to write is not worth failing the build over.
+    let _ = ::std::fs::create_dir_all(&dir);
+    let _ = ::std::fs::write(path, debug_pretty(tokens.clone()));
+}
+
+// TokenStream's Display puts everything on one line. This breaks lines after
+// statements, attributes and blocks, which is enough to read the output.
+fn debug_pretty(tokens: TokenStream) -> String {
+    let mut out = String::new();
+    debug_pretty_tokens(&mut out, tokens, 0);
+    out.push('\n');
+    out
+}
+
+fn debug_pretty_tokens(out: &mut String, tokens: TokenStream, indent: usize) {
+    let mut line_start = true;
+    let mut joint = false;
+    let mut newline = false;
+    let mut pound = false;
+    let mut after_ident = false;
+
+    for token in tokens {
+        let punct = match token {
+            TokenTree::Punct(ref punct) => Some(punct.as_char()),
+            _ => None,
+        };
+        if newline && punct != Some(';') && punct != Some(',') && punct != Some('.') {
+            debug_newline(out, indent);
+            line_start = true;
+        }
+        let tight = match token {
+            TokenTree::Group(ref group) => {
+                after_ident && group.delimiter() != Delimiter::Brace
+                    || pound && group.delimiter() == Delimiter::Bracket
+            }
+            TokenTree::Punct(_) => punct == Some(',') || punct == Some(';') || punct == Some('.'),
+            _ => false,
+        };
+        if !line_start && !joint && !tight {
+            out.push(' ');
+        }
+        newline = false;
+        line_start = false;
+        joint = false;
+        after_ident = false;
+
+        match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::None => ("", ""),
+                };
+                out.push_str(open);
+                if group.delimiter() == Delimiter::Brace {
+                    if !group.stream().is_empty() {
+                        debug_newline(out, indent + 1);
+                        debug_pretty_tokens(out, group.stream(), indent + 1);
+                        debug_newline(out, indent);
+                    }
+                    newline = true;
+                } else {
+                    debug_pretty_tokens(out, group.stream(), indent);
+                    newline = pound && group.delimiter() == Delimiter::Bracket;
+                }
+                out.push_str(close);
+                pound = false;
+            }
+            TokenTree::Punct(punct) => {
+                out.push(punct.as_char());
+                joint = punct.spacing() == Spacing::Joint || punct.as_char() == '.';
+                newline = punct.as_char() == ';';
+                pound = punct.as_char() == '#';
+            }
+            TokenTree::Ident(name) => {
+                out.push_str(&name.to_string());
+                after_ident = true;
+                pound = false;
+            }
+            TokenTree::Literal(literal) => {
+                out.push_str(&literal.to_string());
+                pound = false;
+            }
+        }
+    }
+}
+
+fn debug_newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str("    ");
+    }
+}
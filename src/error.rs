@@ -1,39 +1,92 @@
 use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
 use std::iter::FromIterator;
 
+// One or more errors, each expanding to its own compile_error!.
 pub struct Error {
+    messages: Vec<Message>,
+}
+
+struct Message {
     span: Span,
     msg: String,
+    notes: Vec<String>,
 }
 
 impl Error {
     pub fn new(span: Span, msg: impl Into<String>) -> Self {
         Error {
-            span,
-            msg: msg.into(),
+            messages: vec![Message {
+                span,
+                msg: msg.into(),
+                notes: Vec::new(),
+            }],
+        }
+    }
+
+    // Attaches "help: ..." text to the most recently added error.
+    pub fn help(self, help: impl Into<String>) -> Self {
+        self.attach("help", help.into())
+    }
+
+    // Attaches "note: ..." text to the most recently added error.
+    pub fn note(self, note: impl Into<String>) -> Self {
+        self.attach("note", note.into())
+    }
+
+    fn attach(mut self, kind: &str, text: String) -> Self {
+        if let Some(message) = self.messages.last_mut() {
+            message.notes.push(format!("{}: {}", kind, text));
         }
+        self
+    }
+
+    pub fn combine(&mut self, another: Error) {
+        self.messages.extend(another.messages);
+    }
+}
+
+// Adds the error to the ones collected so far, for parsers that keep going
+// after an error to report everything wrong with the input at once.
+pub fn combine(errors: &mut Option<Error>, err: Error) {
+    match errors {
+        Some(errors) => errors.combine(err),
+        None => *errors = Some(err),
     }
 }
 
 pub fn compile_error(err: Error) -> TokenStream {
+    err.messages
+        .into_iter()
+        .flat_map(message_to_compile_error)
+        .collect()
+}
+
+fn message_to_compile_error(message: Message) -> Vec<TokenTree> {
+    let span = message.span;
+    let mut msg = message.msg;
+    for note in message.notes {
+        msg += "\n= ";
+        msg += &note;
+    }
+
     // compile_error!($msg)
-    TokenStream::from_iter(vec![
-        TokenTree::Ident(Ident::new("compile_error", err.span)),
+    vec![
+        TokenTree::Ident(Ident::new("compile_error", span)),
         TokenTree::Punct({
             let mut punct = Punct::new('!', Spacing::Alone);
-            punct.set_span(err.span);
+            punct.set_span(span);
             punct
         }),
         TokenTree::Group({
             let mut group = Group::new(Delimiter::Brace, {
                 TokenStream::from_iter(vec![TokenTree::Literal({
-                    let mut string = Literal::string(&err.msg);
-                    string.set_span(err.span);
+                    let mut string = Literal::string(&msg);
+                    string.set_span(span);
                     string
                 })])
             });
-            group.set_span(err.span);
+            group.set_span(span);
             group
         }),
-    ])
+    ]
 }
@@ -57,6 +57,80 @@
 //! # fn main() {}
 //! ```
 //!
+//! The function may instead return `Result<TokenStream, E>`. An `Err` is turned
+//! into a compile error by calling the error's `to_compile_error` method, which
+//! `syn::Error` provides.
+//!
+//! ```
+//! # extern crate proc_macro;
+//! #
+//! # use proc_macro::TokenStream;
+//! # use proc_macro_hack::proc_macro_hack;
+//! # use quote::quote;
+//! # use syn::Expr;
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack]
+//! # };
+//! pub fn add_one(input: TokenStream) -> syn::Result<TokenStream> {
+//!     let expr: Expr = syn::parse(input)?;
+//!     Ok(TokenStream::from(quote! {
+//!         1 + (#expr)
+//!     }))
+//! }
+//! #
+//! # fn main() {}
+//! ```
+//!
+//! A macro can also warn about its input without failing the build, for
+//! example to phase out some syntax. Next to every `#[proc_macro_hack]`
+//! function, `proc_macro_hack_warning!(span, message)` produces items that
+//! trigger the warning when included in the macro output, such as at the start
+//! of a block. The warning is reported through the `deprecated` lint as the use
+//! of a deprecated constant.
+//!
+//! ```
+//! # extern crate proc_macro;
+//! #
+//! # use proc_macro::TokenStream;
+//! # use proc_macro_hack::proc_macro_hack;
+//! # use quote::quote;
+//! # use syn::{parse_macro_input, Expr};
+//! use proc_macro::{Delimiter, Group, TokenTree};
+//!
+//! # macro_rules! proc_macro_hack_warning {
+//! #     ($span:expr, $message:expr) => {
+//! #         TokenStream::new()
+//! #     };
+//! # }
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack]
+//! # };
+//! pub fn add_one(input: TokenStream) -> TokenStream {
+//!     let mut output = TokenStream::new();
+//!
+//!     // Older versions took `add_one!(= x)`.
+//!     let mut input = input.into_iter().peekable();
+//!     if let Some(TokenTree::Punct(punct)) = input.peek() {
+//!         if punct.as_char() == '=' {
+//!             let warning = "the `=` is no longer needed in add_one!";
+//!             output.extend(proc_macro_hack_warning!(punct.span(), warning));
+//!             input.next();
+//!         }
+//!     }
+//!
+//!     let input = input.collect::<TokenStream>();
+//!     let expr = parse_macro_input!(input as Expr);
+//!     output.extend(TokenStream::from(quote! {
+//!         1 + (#expr)
+//!     }));
+//!     TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, output)))
+//! }
+//! #
+//! # fn main() {}
+//! ```
+//!
 //! ## The declaration crate
 //!
 //! This crate is allowed to contain other public things if you need, for
@@ -80,6 +154,106 @@
 //! # fn main() {}
 //! ```
 //!
+//! A re-export with restricted visibility, such as `pub(crate) use`, makes the
+//! macro usable from any module of the declaration crate without exporting it
+//! to downstream crates. Its path has to start with `::` or `crate::`, as in
+//! `pub(crate) use ::demo_hack_impl::add_one;`.
+//!
+//! When several macros are re-exported together, attributes that apply to only
+//! one of them go in a `for` block in the attribute arguments, since Rust does
+//! not allow attributes inside of a `use` tree. This includes `#[cfg(...)]`,
+//! for a macro that the implementation crate provides only under some feature.
+//!
+//! ```
+//! # use proc_macro_hack::proc_macro_hack;
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack(
+//!     for m2 {
+//!         /// Documentation for m2 only.
+//!         #[deprecated = "use m1 instead"]
+//!     }
+//! )]
+//! pub use demo_hack_impl::{m1, m2};
+//! # };
+//! #
+//! # fn main() {}
+//! ```
+//!
+//! A `#[proc_macro_hack(...)]` attribute inside of a `for` block gives that one
+//! macro its own options, in addition to any options listed for the whole
+//! re-export.
+//!
+//! ```
+//! # use proc_macro_hack::proc_macro_hack;
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack(
+//!     for m2 {
+//!         #[proc_macro_hack(support_nested)]
+//!     }
+//! )]
+//! pub use demo_hack_impl::{m1, m2};
+//! # };
+//! #
+//! # fn main() {}
+//! ```
+//!
+//! A macro that generates items rather than an expression is re-exported with
+//! `#[proc_macro_hack(items)]`. Its output is placed directly into the module
+//! containing the call, and it may be called any number of times in the same
+//! module.
+//!
+//! ```
+//! # use proc_macro_hack::proc_macro_hack;
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack(items)]
+//! pub use demo_hack_impl::define_consts;
+//! # };
+//! #
+//! # fn main() {}
+//! ```
+//!
+//! An implementation function taking two token streams, `args` and `input`,
+//! like a `#[proc_macro_attribute]`, is called as `repeat!(args => input)`
+//! where the first `=>` outside of any brackets separates the two. Its
+//! re-export needs `#[proc_macro_hack(attribute)]`.
+//!
+//! ```
+//! # use proc_macro_hack::proc_macro_hack;
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack(attribute)]
+//! pub use demo_hack_impl::repeat;
+//! # };
+//! #
+//! # fn main() {}
+//! ```
+//!
+//! A glob re-export covers every macro that the implementation crate lists in
+//! a call to `proc_macro_hack::manifest!` at its crate root, such as
+//! `proc_macro_hack::manifest!(add_one, repeat);`. A \#\[proc_macro_hack\]
+//! function left out of the list is an error in the implementation crate, and
+//! a listed name that isn't a \#\[proc_macro_hack\] function fails to resolve
+//! where the glob is re-exported. Options for individual macros still go in
+//! `for` blocks.
+//!
+//! ```
+//! # use proc_macro_hack::proc_macro_hack;
+//! #
+//! # const IGNORE: &str = stringify! {
+//! #[proc_macro_hack(
+//!     for repeat {
+//!         #[proc_macro_hack(attribute)]
+//!     }
+//! )]
+//! pub use demo_hack_impl::*;
+//! # };
+//! #
+//! # fn main() {}
+//! ```
+//!
 //! Both crates depend on `proc-macro-hack`:
 //!
 //! ```toml
@@ -118,19 +292,31 @@
 //!
 //! # Limitations
 //!
-//! - Only proc macros in expression position are supported. Proc macros in
-//!   pattern position ([#20]) are not supported.
+//! - Proc macros in pattern position ([#20]) can't be invoked directly. With
+//!   `#[proc_macro_hack(pattern)]` on the re-export, the macro is instead
+//!   invoked as `my_pattern!(NAME = input)` in statement or item position,
+//!   which defines a macro `NAME!()` expanding to the pattern. Variables bound
+//!   by such a pattern are hygienic to `NAME!` and can't be used after it, so
+//!   this suits patterns made of constants, literals, ranges and wildcards.
+//!   Proc macros in type position work the same way using
+//!   `#[proc_macro_hack(type_position)]`.
 //!
 //! - By default, nested invocations are not supported i.e. the code emitted by
 //!   a proc-macro-hack macro invocation cannot contain recursive calls to the
 //!   same proc-macro-hack macro nor calls to any other proc-macro-hack macros.
-//!   Use [`proc-macro-nested`] if you require support for nested invocations.
+//!   Use [`proc-macro-nested`] 0.1.8 or newer if you require support for
+//!   nested invocations.
 //!
 //! - By default, hygiene is structured such that the expanded code can't refer
 //!   to local variables other than those passed by name somewhere in the macro
 //!   input. If your macro must refer to *local* variables that don't get named
-//!   in the macro input, use `#[proc_macro_hack(fake_call_site)]` on the
-//!   re-export in your declaration crate. *Most macros won't need this.*
+//!   in the macro input, use `#[proc_macro_hack(hygiene = "call_site")]` on
+//!   the re-export in your declaration crate. *Most macros won't need this.*
+//!   This is a new spelling of `#[proc_macro_hack(fake_call_site)]`, which
+//!   keeps working. The default can be written out as `hygiene =
+//!   "mixed_site"`, since it is the closest match to how a native
+//!   `#[proc_macro]` using `Span::mixed_site()` behaves. There is no
+//!   `"def_site"` option because `Span::def_site()` is unstable.
 //!
 //! - On compilers that are new enough to natively support proc macros in
 //!   expression position, proc-macro-hack does not automatically use that
@@ -138,11 +324,44 @@
 //!   implementations. To opt in to compiling your macro to native
 //!   `#[proc_macro]` on sufficiently new compilers, use
 //!   `#[proc_macro_hack(only_hack_old_rustc)]` on the re-export in your
-//!   declaration crate.
+//!   declaration crate. On a grouped or glob re-export this applies to every
+//!   macro in it, and individual macros can be kept on the hack with
+//!   `for m { #[proc_macro_hack(always_hack)] }`. The compiler
+//!   version check can be overridden by setting
+//!   `PROC_MACRO_HACK_MODE=hack` or `PROC_MACRO_HACK_MODE=native` in the
+//!   environment of the build, for example to reproduce a bug in the hack on a
+//!   new compiler or to use native support on a toolchain whose version can't
+//!   be detected.
+//!
+//! - Errors in tokens that the macro creates rather than takes from its input
+//!   point at the caller's invocation only when the implementation crate is
+//!   built with Rust 1.88 or newer, which lets proc macros read the location of
+//!   a span. With older compilers, including the 1.31 through 1.44 compilers
+//!   that rely on the hack, they point at the `#[proc_macro_hack]` re-export.
 //!
 //! [#10]: https://github.com/dtolnay/proc-macro-hack/issues/10
 //! [#20]: https://github.com/dtolnay/proc-macro-hack/issues/20
 //! [`proc-macro-nested`]: https://docs.rs/proc-macro-nested
+//!
+//! # Debugging
+//!
+//! Setting `PROC_MACRO_HACK_DEBUG` to a directory while building writes every
+//! stage of the expansion there as a file, keyed by the crate being compiled,
+//! the name of the implementation function and a count of that macro's
+//! expansions so far in the crate:
+//!
+//! - `<crate>.<macro>.<N>.export.rs` — the `macro_rules!` generated from the
+//!   `#[proc_macro_hack]` re-export in the declaration crate,
+//! - `<crate>.<macro>.<N>.enum.rs` — the `enum ProcMacroHack` that a call of
+//!   the macro hands to the implementation crate,
+//! - `<crate>.<macro>.<N>.derive.rs` — the `macro_rules! proc_macro_call`
+//!   carrying the macro output back, or the output itself for `items`,
+//! - `<crate>.<macro>.<N>.native.rs` — the output when the macro is expanded
+//!   natively.
+//!
+//! Implementation crates only include the code for this when they are built
+//! with the variable set. Changing the variable rebuilds proc-macro-hack and
+//! every crate that uses it.
 
 #![recursion_limit = "512"]
 #![allow(
@@ -160,22 +379,34 @@ extern crate proc_macro;
 #[macro_use]
 mod quote;
 
+mod debug;
 mod error;
 mod iter;
 mod parse;
-
+use crate::debug::{debug_dump, debug_enabled};
 use crate::error::{compile_error, Error};
 use crate::iter::Iter;
 use crate::parse::{
     parse_define_args, parse_enum_hack, parse_export_args, parse_fake_call_site, parse_input,
+    parse_manifest,
 };
-use proc_macro::{Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+use proc_macro::{Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use std::cell::RefCell;
+use std::collections::HashMap as Map;
 use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-type Visibility = Option<Ident>;
+// pub, pub(crate), pub(in some::path)
+#[derive(Clone)]
+enum Visibility {
+    Public(Ident),
+    Restricted(Ident, Group),
+    Inherited,
+}
 
 enum Input {
     Export(Export),
+    Glob(Glob),
     Define(Define),
 }
 
@@ -183,20 +414,41 @@ enum Input {
 struct Export {
     attrs: TokenStream,
     vis: Visibility,
-    from: Ident,
+    from: Path,
     macros: Vec<Macro>,
 }
 
+// #[doc = "..."] pub use demo_hack_impl::*;
+struct Glob {
+    attrs: TokenStream,
+    vis: Visibility,
+    from: Path,
+}
+
+// ::demo_hack_impl::exprs
+#[derive(Clone)]
+struct Path {
+    global: bool,
+    segments: Vec<Ident>,
+}
+
 // pub fn m1(input: TokenStream) -> TokenStream { ... }
+// pub fn m2(input: TokenStream) -> Result<TokenStream, syn::Error> { ... }
 struct Define {
     attrs: TokenStream,
     name: Ident,
     body: TokenStream,
+    fallible: bool,
+    // fn(args: TokenStream, input: TokenStream), called as `m!(args => input)`.
+    takes_args: bool,
 }
 
+// #[doc = "..."] #[proc_macro_hack(support_nested)] m2 as qrst
 struct Macro {
+    attrs: TokenStream,
     name: Ident,
     export_as: Ident,
+    args: Option<ExportArgs>,
 }
 
 #[proc_macro_attribute]
@@ -208,17 +460,58 @@ pub fn proc_macro_hack(args: TokenStream, input: TokenStream) -> TokenStream {
 
 fn expand_proc_macro_hack(args: Iter, input: Iter) -> Result<TokenStream, Error> {
     match parse_input(input)? {
-        Input::Export(export) => {
-            let args = parse_export_args(args)?;
-            Ok(expand_export(export, args))
+        Input::Export(mut export) => {
+            let args = parse_export_args(args, &mut export.macros)?;
+            let keys = if debug_enabled() {
+                export
+                    .macros
+                    .iter()
+                    .map(|mac| debug_key(&mac.name))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let output = expand_export(export, args);
+            for key in keys {
+                debug_dump(&key, "export", &output);
+            }
+            Ok(output)
         }
+        Input::Glob(glob) => Ok(expand_glob(glob, args.collect())),
         Input::Define(define) => {
             parse_define_args(args)?;
-            Ok(expand_define(define))
+            let unlisted = check_defined(&define.name);
+            let mut output = expand_define(define);
+            if let Err(err) = unlisted {
+                output.extend(compile_error(err));
+            }
+            Ok(output)
         }
     }
 }
 
+// The names of the macros in the re-export, and how many re-exports this crate
+// has seen before it.
+// Files are keyed by the name of the implementation function and how many
+// times that macro has been seen in the crate being compiled, the same key the
+// implementation crate uses for the later stages.
+fn debug_key(name: &Ident) -> String {
+    thread_local! {
+        static COUNTERS: RefCell<Map<String, usize>> = RefCell::new(Map::new());
+    }
+    let name = unraw(name).to_string();
+    let id = COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let counter = counters.entry(name.clone()).or_insert(0);
+        *counter += 1;
+        *counter - 1
+    });
+    format!("{}.{}", name, id)
+}
+
+// The part of debug.rs that expand_define pastes into implementation crates.
+const DEBUG_PASTE_MARKER: &str = "// ---- pasted into implementation crates from here on ----";
+
 #[doc(hidden)]
 #[proc_macro_derive(ProcMacroHack)]
 pub fn enum_hack(input: TokenStream) -> TokenStream {
@@ -256,86 +549,415 @@ fn expand_fake_call_site(args: Iter, input: Iter) -> Result<TokenStream, Error>
     })
 }
 
+#[doc(hidden)]
+#[proc_macro_attribute]
+pub fn unique_items(args: TokenStream, input: TokenStream) -> TokenStream {
+    let _ = args;
+    expand_unique_items(input)
+}
+
+/// Lists the macros of an implementation crate that a glob re-export
+/// `pub use impl_crate::*` covers.
+///
+/// Called once at the crate root of the implementation crate, naming every
+/// \#\[proc_macro_hack\] function in it. See the [crate-level documentation]
+/// for an example.
+///
+/// [crate-level documentation]: crate
+#[proc_macro]
+pub fn manifest(input: TokenStream) -> TokenStream {
+    let ref mut input = iter::new(input);
+    let names = match parse_manifest(input) {
+        Ok(names) => names,
+        Err(err) => return compile_error(err),
+    };
+    let unlisted = check_manifest(&names);
+    let mut output = expand_manifest(names);
+    if let Err(err) = unlisted {
+        output.extend(compile_error(err));
+    }
+    output
+}
+
+// The #[proc_macro_hack] functions and the manifest seen so far in the crate
+// being compiled. Expansion order between them is unspecified, so whichever of
+// a function and the manifest expands second reports a function missing from
+// the manifest. Only names are kept since a span is not valid outside of the
+// expansion it came from.
+#[derive(Default)]
+struct Registry {
+    defined: Vec<String>,
+    manifest: Option<Vec<String>>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+fn check_defined(name: &Ident) -> Result<(), Error> {
+    let string = unraw(name).to_string();
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let unlisted = match &registry.manifest {
+            Some(manifest) => !manifest.contains(&string),
+            None => false,
+        };
+        registry.defined.push(string.clone());
+        if unlisted {
+            Err(unlisted_error(name.span(), &[string]))
+        } else {
+            Ok(())
+        }
+    })
+}
+
+fn check_manifest(names: &[Ident]) -> Result<(), Error> {
+    let manifest: Vec<String> = names.iter().map(|name| unraw(name).to_string()).collect();
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let unlisted: Vec<String> = registry
+            .defined
+            .iter()
+            .filter(|name| !manifest.contains(name))
+            .cloned()
+            .collect();
+        registry.manifest = Some(manifest);
+        if unlisted.is_empty() {
+            Ok(())
+        } else {
+            Err(unlisted_error(Span::call_site(), &unlisted))
+        }
+    })
+}
+
+fn unlisted_error(span: Span, names: &[String]) -> Error {
+    let list = names
+        .iter()
+        .map(|name| format!("`{}`", name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let msg = if names.len() == 1 {
+        format!(
+            "#[proc_macro_hack] function {} is missing from proc_macro_hack::manifest!",
+            list
+        )
+    } else {
+        format!(
+            "#[proc_macro_hack] functions {} are missing from proc_macro_hack::manifest!",
+            list
+        )
+    };
+    Error::new(span, msg)
+        .help("a glob re-export of this crate only covers the macros listed in the manifest")
+}
+
+// #[proc_macro]
+// pub fn proc_macro_hack_manifest(input: TokenStream) -> TokenStream {
+//     // (args) #[attrs] pub use path
+//     // becomes
+//     // #[::proc_macro_hack::proc_macro_hack(args)] #[attrs] pub use path::{m1, m2};
+// }
+//
+// The names are checked where the re-export expands them: a name that isn't a
+// #[proc_macro_hack] function fails to resolve its hidden import there.
+fn expand_manifest(names: Vec<Ident>) -> TokenStream {
+    let mut list = String::new();
+    for name in names {
+        if !list.is_empty() {
+            list += ", ";
+        }
+        list += &name.to_string();
+    }
+    let list = TokenStream::from(TokenTree::Literal(Literal::string(&list)));
+
+    quote! {
+        mod proc_macro_hack_manifest {
+            extern crate proc_macro;
+            pub use self::proc_macro::*;
+        }
+
+        #[doc(hidden)]
+        #[proc_macro]
+        pub fn proc_macro_hack_manifest(
+            input: proc_macro_hack_manifest::TokenStream,
+        ) -> proc_macro_hack_manifest::TokenStream {
+            use proc_macro_hack_manifest::{Delimiter, Group, Punct, Spacing, TokenStream, TokenTree};
+            use std::iter::FromIterator;
+
+            let mut input = input.into_iter();
+            let mut attr: TokenStream = "::proc_macro_hack::proc_macro_hack".parse().unwrap();
+            attr.extend(input.next());
+
+            let mut output = TokenStream::from_iter(vec![
+                TokenTree::Punct(Punct::new('#', Spacing::Alone)),
+                TokenTree::Group(Group::new(Delimiter::Bracket, attr)),
+            ]);
+            output.extend(input);
+            output.extend(format!("::{{{}}};", #list).parse::<TokenStream>().unwrap());
+            output
+        }
+    }
+}
+
+// pub use demo_hack_impl::*;
+//
+// The implementation crate's manifest turns this back into the usual
+// re-export, naming every macro.
+fn expand_glob(glob: Glob, args: TokenStream) -> TokenStream {
+    let Glob { attrs, vis, from } = glob;
+    quote! {
+        #from::proc_macro_hack_manifest! {
+            (#args) #attrs #vis use #from
+        }
+    }
+}
+
+// Every invocation of an `items` macro in the same module declares an enum, so
+// each one gets a distinct name. Proc macros in one crate are expanded by the
+// same process, which makes a counter enough to tell them apart.
+fn expand_unique_items(input: TokenStream) -> TokenStream {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut output = TokenStream::new();
+    let mut after_enum = false;
+    for tt in input {
+        let tt = match tt {
+            TokenTree::Ident(ident) if after_enum => {
+                let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+                let name = format!("{}{}", ident, n);
+                TokenTree::Ident(Ident::new(&name, ident.span()))
+            }
+            tt => tt,
+        };
+        after_enum = match &tt {
+            TokenTree::Ident(ident) => ident.to_string() == "enum",
+            _ => false,
+        };
+        output.extend(TokenStream::from(tt));
+    }
+    output
+}
+
+#[derive(Clone)]
 struct ExportArgs {
     support_nested: bool,
     internal_macro_calls: u16,
-    fake_call_site: bool,
+    hygiene: Hygiene,
     only_hack_old_rustc: bool,
+    always_hack: Option<Span>,
+    position: Option<(Position, Span)>,
+    items: Option<Span>,
+    attribute: Option<Span>,
+}
+
+// Where identifiers in the macro output that were not part of the input are
+// resolved. There is no def_site option since Span::def_site is unstable.
+#[derive(Copy, Clone, PartialEq)]
+enum Hygiene {
+    // Local variables at the re-export, everything else at the call site. This
+    // is what spans from the macro_rules in the declaration crate give.
+    MixedSite,
+    // Everything at the call site, by giving the derive the span of the input.
+    CallSite,
+}
+
+// Positions other than expressions, in which the macro output is handed back
+// through a helper macro named by the caller.
+#[derive(Copy, Clone, PartialEq)]
+enum Position {
+    Pattern,
+    Type,
+}
+
+impl Position {
+    fn arg(self) -> &'static str {
+        match self {
+            Position::Pattern => "pattern",
+            Position::Type => "type_position",
+        }
+    }
+
+    fn variant(self) -> &'static str {
+        match self {
+            Position::Pattern => "Pattern",
+            Position::Type => "Type",
+        }
+    }
+}
+
+// Whether the re-export can be a plain `use` of the native #[proc_macro].
+fn use_native(args: &ExportArgs) -> bool {
+    if cfg!(need_proc_macro_hack) || args.always_hack.is_some() || args.position.is_some() {
+        return false;
+    }
+    args.only_hack_old_rustc
 }
 
 fn expand_export(export: Export, args: ExportArgs) -> TokenStream {
-    if args.only_hack_old_rustc && cfg!(not(need_proc_macro_hack)) {
-        return expand_export_nohack(export);
+    let Export {
+        attrs,
+        vis,
+        from,
+        macros,
+    } = export;
+
+    let (nohack, macros): (Vec<Macro>, Vec<Macro>) = macros
+        .into_iter()
+        .partition(|mac| use_native(mac.args.as_ref().unwrap_or(&args)));
+
+    let nohack = if nohack.is_empty() {
+        quote!()
+    } else {
+        expand_export_nohack(Export {
+            attrs: attrs.clone(),
+            vis: vis.clone(),
+            from: from.clone(),
+            macros: nohack,
+        })
+    };
+
+    if macros.is_empty() {
+        return nohack;
     }
 
+    let export = Export {
+        attrs,
+        vis,
+        from,
+        macros,
+    };
     let dummy = dummy_name_for_export(&export);
 
     let attrs = export.attrs;
     let vis = export.vis;
     let macro_export = match vis {
-        Some(_) => quote!(#[macro_export]),
-        None => quote!(),
+        Visibility::Public(_) => quote!(#[macro_export]),
+        Visibility::Restricted(..) | Visibility::Inherited => quote!(),
     };
-    let crate_prefix = vis.as_ref().map(|_| quote!($crate::));
-    let enum_variant = if args.support_nested {
-        if args.internal_macro_calls == 0 {
-            Ident::new("Nested", Span::call_site())
-        } else {
-            let name = format!("Nested{}", args.internal_macro_calls);
-            Ident::new(&name, Span::call_site())
-        }
-    } else {
-        Ident::new("Value", Span::call_site())
+    let crate_prefix = match vis {
+        Visibility::Public(_) => Some(quote!($crate::)),
+        Visibility::Restricted(..) | Visibility::Inherited => None,
+    };
+    // Restricted macros are not #[macro_export]ed so there is no $crate path
+    // to the hidden imports; name everything by absolute path instead.
+    let restricted = match vis {
+        Visibility::Restricted(..) => true,
+        Visibility::Public(_) | Visibility::Inherited => false,
     };
 
+    let version = version_literal();
+
     let from = export.from;
-    let mut actual_names = TokenStream::new();
-    let mut export_dispatch = TokenStream::new();
-    let mut export_call_site = TokenStream::new();
+    let mut actual_names = Vec::new();
+    let mut export_dispatch = Vec::new();
+    let mut export_call_site = Vec::new();
+    let mut export_unique_items = Vec::new();
+    let mut gated_imports = TokenStream::new();
     let mut macro_rules = TokenStream::new();
-    for Macro { name, export_as } in &export.macros {
+    for mac in &export.macros {
+        let args = mac.args.as_ref().unwrap_or(&args);
+        let name = &mac.name;
+        let export_as = &mac.export_as;
+        let macro_attrs = &mac.attrs;
         let hacked = hacked_proc_macro_name(name);
         let dispatch = dispatch_macro_name(name);
         let call_site = call_site_macro_name(name);
+        let unique_items = unique_items_macro_name(name);
+        let macro_cfg = cfg_attrs(macro_attrs);
 
-        if !actual_names.is_empty() {
-            actual_names.extend(quote!(,));
-        }
-        actual_names.extend(quote!(#hacked));
-
-        if !export_dispatch.is_empty() {
-            export_dispatch.extend(quote!(,));
+        if macro_cfg.is_empty() {
+            actual_names.push(quote!(#hacked));
+            if args.support_nested {
+                export_dispatch.push(quote!(dispatch_calls as #dispatch));
+            }
+            if args.hygiene == Hygiene::CallSite {
+                export_call_site.push(quote!(fake_call_site as #call_site));
+            }
+            if args.items.is_some() {
+                export_unique_items.push(quote!(unique_items as #unique_items));
+            }
+        } else if !restricted {
+            // Imports for a macro that is itself conditional can't share a
+            // `use` with the others.
+            gated_imports.extend(quote! {
+                #[doc(hidden)]
+                #macro_cfg
+                #vis use #from::#hacked;
+            });
+            if args.support_nested {
+                gated_imports.extend(quote! {
+                    #[doc(hidden)]
+                    #macro_cfg
+                    #vis use proc_macro_nested::dispatch_calls as #dispatch;
+                });
+            }
+            if args.hygiene == Hygiene::CallSite {
+                gated_imports.extend(quote! {
+                    #[doc(hidden)]
+                    #macro_cfg
+                    #vis use proc_macro_hack::fake_call_site as #call_site;
+                });
+            }
+            if args.items.is_some() {
+                gated_imports.extend(quote! {
+                    #[doc(hidden)]
+                    #macro_cfg
+                    #vis use proc_macro_hack::unique_items as #unique_items;
+                });
+            }
         }
-        export_dispatch.extend(quote!(dispatch as #dispatch));
 
-        if !export_call_site.is_empty() {
-            export_call_site.extend(quote!(,));
-        }
-        export_call_site.extend(quote!(fake_call_site as #call_site));
+        let (derive_path, call_site_path, dispatch_path, unique_items_path) = if restricted {
+            (
+                quote!(#from::#hacked),
+                quote!(::proc_macro_hack::fake_call_site),
+                quote!(::proc_macro_nested::dispatch_calls),
+                quote!(::proc_macro_hack::unique_items),
+            )
+        } else {
+            (
+                quote!(#crate_prefix #hacked),
+                quote!(#crate_prefix #call_site),
+                quote!(#crate_prefix #dispatch),
+                quote!(#crate_prefix #unique_items),
+            )
+        };
 
-        let do_derive = if !args.fake_call_site {
+        let do_derive = if args.hygiene == Hygiene::MixedSite {
             quote! {
-                #[derive(#crate_prefix #hacked)]
+                #[derive(#derive_path)]
             }
-        } else if crate_prefix.is_some() {
+        } else if crate_prefix.is_some() || restricted {
             quote! {
-                use #crate_prefix #hacked;
-                #[#crate_prefix #call_site ($($proc_macro)*)]
+                use #derive_path;
+                #[#call_site_path ($($proc_macro)*)]
                 #[derive(#hacked)]
             }
         } else {
             quote! {
-                #[#call_site ($($proc_macro)*)]
+                #[#call_site_path ($($proc_macro)*)]
                 #[derive(#hacked)]
             }
         };
 
+        let (enum_variant, protocol) = if args.support_nested {
+            let variant = if args.internal_macro_calls == 0 {
+                Ident::new("Calls", Span::call_site())
+            } else {
+                let name = format!("Calls{}", args.internal_macro_calls);
+                Ident::new(&name, Span::call_site())
+            };
+            (variant, protocol_literal(3))
+        } else {
+            (Ident::new("Value", Span::call_site()), protocol_literal(1))
+        };
+
         let proc_macro_call = if args.support_nested {
             let extra_bangs = (0..args.internal_macro_calls)
                 .map(|_| TokenTree::Punct(Punct::new('!', Spacing::Alone)))
                 .collect::<TokenStream>();
             quote! {
-                #crate_prefix #dispatch! { ($($proc_macro)*) #extra_bangs }
+                #dispatch_path! { ($($proc_macro)*) #extra_bangs }
             }
         } else {
             quote! {
@@ -343,29 +965,82 @@ fn expand_export(export: Export, args: ExportArgs) -> TokenStream {
             }
         };
 
-        macro_rules.extend(quote! {
-            #attrs
-            #macro_export
-            macro_rules! #export_as {
+        let rule = if let Some((position, _)) = args.position {
+            // The output is handed back as a macro named by the caller, which
+            // they invoke in pattern or type position after this one.
+            let protocol = protocol_literal(2);
+            let variant = Ident::new(position.variant(), Span::call_site());
+            quote! {
+                ($name:ident = $($proc_macro:tt)*) => {
+                    #do_derive
+                    #[allow(dead_code, non_camel_case_types)]
+                    enum $name {
+                        #variant = (stringify! { $($proc_macro)* }, #protocol, #version).1,
+                    }
+                };
+            }
+        } else if args.attribute.is_some() {
+            // The derive splits the args from the input at the first `=>`. A
+            // macro_rules could only do that by calling itself, which the
+            // compiler rejects for macro_export macros called by path from
+            // within the declaration crate.
+            let protocol = protocol_literal(4);
+            quote! {
                 ($($proc_macro:tt)*) => {{
                     #do_derive
                     #[allow(dead_code)]
                     enum ProcMacroHack {
-                        #enum_variant = (stringify! { $($proc_macro)* }, 0).1,
+                        Attribute = (stringify! { $($proc_macro)* }, #protocol, #version).1,
+                    }
+                    proc_macro_call!()
+                }};
+            }
+        } else if args.items.is_some() {
+            // The derive emits the items next to the enum, directly into the
+            // module containing the call.
+            let protocol = protocol_literal(2);
+            quote! {
+                ($($proc_macro:tt)*) => {
+                    #[#unique_items_path]
+                    #do_derive
+                    #[allow(dead_code)]
+                    enum ProcMacroHack {
+                        Items = (stringify! { $($proc_macro)* }, #protocol, #version).1,
+                    }
+                };
+            }
+        } else {
+            quote! {
+                ($($proc_macro:tt)*) => {{
+                    #do_derive
+                    #[allow(dead_code)]
+                    enum ProcMacroHack {
+                        #enum_variant = (stringify! { $($proc_macro)* }, #protocol, #version).1,
                     }
                     #proc_macro_call
                 }};
             }
+        };
+
+        macro_rules.extend(quote! {
+            #attrs
+            #macro_attrs
+            #macro_export
+            macro_rules! #export_as {
+                #rule
+            }
         });
-    }
 
-    if export.macros.len() != 1 {
-        export_dispatch = quote!({#export_dispatch});
-        export_call_site = quote!({#export_call_site});
-        actual_names = quote!({#actual_names});
+        if restricted {
+            macro_rules.extend(quote! {
+                #macro_cfg
+                #vis use #export_as;
+            });
+        }
     }
 
-    let export_dispatch = if args.support_nested {
+    let export_dispatch = if !export_dispatch.is_empty() && !restricted {
+        let export_dispatch = use_tree(export_dispatch);
         quote! {
             #[doc(hidden)]
             #vis use proc_macro_nested::#export_dispatch;
@@ -374,7 +1049,8 @@ fn expand_export(export: Export, args: ExportArgs) -> TokenStream {
         quote!()
     };
 
-    let export_call_site = if args.fake_call_site {
+    let export_call_site = if !export_call_site.is_empty() && !restricted {
+        let export_call_site = use_tree(export_call_site);
         quote! {
             #[doc(hidden)]
             #vis use proc_macro_hack::#export_call_site;
@@ -383,41 +1059,104 @@ fn expand_export(export: Export, args: ExportArgs) -> TokenStream {
         quote!()
     };
 
+    let export_unique_items = if !export_unique_items.is_empty() && !restricted {
+        let export_unique_items = use_tree(export_unique_items);
+        quote! {
+            #[doc(hidden)]
+            #vis use proc_macro_hack::#export_unique_items;
+        }
+    } else {
+        quote!()
+    };
+
+    let import_actual_names = if actual_names.is_empty() || restricted {
+        quote!()
+    } else {
+        let actual_names = use_tree(actual_names);
+        quote! {
+            #[doc(hidden)]
+            #vis use #from::#actual_names;
+        }
+    };
+
     let expanded = quote! {
-        #[doc(hidden)]
-        #vis use #from::#actual_names;
+        #import_actual_names
 
         #export_dispatch
         #export_call_site
+        #export_unique_items
+        #gated_imports
 
         #macro_rules
     };
 
-    wrap_in_enum_hack(dummy, expanded)
+    let hack = wrap_in_enum_hack(dummy, expanded);
+
+    quote! {
+        #nohack
+        #hack
+    }
+}
+
+// The #[cfg(...)] and #[cfg_attr(...)] among the attributes of one macro in a
+// `for` block, which apply to every item generated for that macro and not only
+// to its macro_rules. Those on the re-export itself never get here: rustc
+// evaluates them before it runs the attribute.
+fn cfg_attrs(attrs: &TokenStream) -> TokenStream {
+    let mut cfg = TokenStream::new();
+    let mut tokens = attrs.clone().into_iter();
+    while let (Some(pound), Some(attr)) = (tokens.next(), tokens.next()) {
+        let is_cfg = match &attr {
+            TokenTree::Group(group) => match group.stream().into_iter().next() {
+                Some(TokenTree::Ident(ident)) => {
+                    let ident = ident.to_string();
+                    ident == "cfg" || ident == "cfg_attr"
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if is_cfg {
+            cfg.extend(vec![pound, attr]);
+        }
+    }
+    cfg
+}
+
+// a as b, c as d  ->  {a as b, c as d}
+fn use_tree(items: Vec<TokenStream>) -> TokenStream {
+    let len = items.len();
+    let mut tree = TokenStream::new();
+    for item in items {
+        if !tree.is_empty() {
+            tree.extend(quote!(,));
+        }
+        tree.extend(item);
+    }
+    if len != 1 {
+        tree = quote!({#tree});
+    }
+    tree
 }
 
 fn expand_export_nohack(export: Export) -> TokenStream {
     let attrs = export.attrs;
     let vis = export.vis;
     let from = export.from;
-    let mut names = TokenStream::new();
-
-    for Macro { name, export_as } in &export.macros {
-        let pub_name = pub_proc_macro_name(name);
-        if !names.is_empty() {
-            names.extend(quote!(,));
-        }
-        names.extend(quote!(#pub_name as #export_as));
-    }
+    let mut uses = TokenStream::new();
 
-    if export.macros.len() != 1 {
-        names = quote!({#names});
+    for mac in &export.macros {
+        let macro_attrs = &mac.attrs;
+        let pub_name = pub_proc_macro_name(&mac.name);
+        let export_as = &mac.export_as;
+        uses.extend(quote! {
+            #attrs
+            #macro_attrs
+            #vis use #from::#pub_name as #export_as;
+        });
     }
 
-    quote! {
-        #attrs
-        #vis use #from::#names;
-    }
+    uses
 }
 
 fn expand_define(define: Define) -> TokenStream {
@@ -426,120 +1165,724 @@ fn expand_define(define: Define) -> TokenStream {
     let pub_name = pub_proc_macro_name(&name);
     let hacked = hacked_proc_macro_name(&name);
     let body = define.body;
+    let version = version_literal();
+    let protocol = protocol_literal(PROTOCOL_VERSION);
 
-    quote! {
-        mod #pub_name {
-            extern crate proc_macro;
-            pub use self::proc_macro::*;
+    let params = if define.takes_args {
+        quote!(args, input)
+    } else {
+        quote!(input)
+    };
+
+    let takes_args = if define.takes_args {
+        quote!(true)
+    } else {
+        quote!(false)
+    };
+
+    let call = if define.fallible {
+        quote! {
+            match #name(#params) {
+                Ok(output) => output,
+                Err(error) => #pub_name::TokenStream::from(error.to_compile_error()),
+            }
+        }
+    } else {
+        quote! {
+            #name(#params)
         }
+    };
 
-        #attrs
-        #[doc(hidden)]
-        #[proc_macro_derive(#hacked)]
-        pub fn #hacked(input: #pub_name::TokenStream) -> #pub_name::TokenStream {
-            use std::iter::FromIterator;
+    // Macros that take args get them out of everything between the
+    // parentheses of `m!(args => input)`. A missing `=>` becomes the output so
+    // that the error lands on the call like any other.
+    let call = if define.takes_args {
+        quote! {
+            match #pub_name::split_args(input) {
+                Some((args, input)) => #call,
+                None => #pub_name::compile_error(concat!(
+                    "expected `=>` between the arguments and the input of `",
+                    stringify!(#name),
+                    "!`",
+                )),
+            }
+        }
+    } else {
+        call
+    };
+
+    // Telling the tokens created by the macro apart from those of its input
+    // takes the location accessors of Span from Rust 1.88.
+    let respan = if cfg!(no_span_locations) {
+        quote! {
+            pub fn respan(output: TokenStream, _input: &TokenStream) -> TokenStream {
+                output
+            }
+        }
+    } else {
+        quote! {
+            // Tokens created by the macro with Span::call_site() would point at
+            // the #[derive] written by the declaration crate, which is the
+            // #[proc_macro_hack] re-export. Tokens taken from the input already
+            // point at the caller's invocation and are left alone. The created
+            // ones keep their hygiene but move into the invocation: onto the
+            // input token they were printed from if the macro turned its input
+            // into a string and back, otherwise onto the start of the input.
+            #[allow(clippy::incompatible_msrv)]
+            pub fn respan(output: TokenStream, input: &TokenStream) -> TokenStream {
+                let mut input_leaves = Vec::new();
+                flatten(input.clone(), &mut |token| {
+                    input_leaves.push((leaf_text(token), token.span()));
+                });
+                let first = match input_leaves.first() {
+                    Some(&(_, span)) => span,
+                    None => return output,
+                };
+
+                let call_site = Location::of(Span::call_site());
+                let mut output_leaves = Vec::new();
+                flatten(output.clone(), &mut |token| {
+                    output_leaves.push(if call_site.is_at(token.span()) {
+                        Some(leaf_text(token))
+                    } else {
+                        None
+                    });
+                });
+
+                // The first run of created tokens spelling out the whole input,
+                // found in linear time by Knuth-Morris-Pratt. fallback[i] is the
+                // length of the longest proper prefix of the input that ends at
+                // input_leaves[i].
+                let n = input_leaves.len();
+                let mut fallback = vec![0; n];
+                let mut len = 0;
+                for i in 1..n {
+                    while len > 0 && input_leaves[i].0 != input_leaves[len].0 {
+                        len = fallback[len - 1];
+                    }
+                    if input_leaves[i].0 == input_leaves[len].0 {
+                        len += 1;
+                    }
+                    fallback[i] = len;
+                }
+                let mut printed = None;
+                let mut len = 0;
+                for (i, leaf) in output_leaves.iter().enumerate() {
+                    let text = match leaf {
+                        Some(text) => text,
+                        None => {
+                            len = 0;
+                            continue;
+                        }
+                    };
+                    while len > 0 && *text != input_leaves[len].0 {
+                        len = fallback[len - 1];
+                    }
+                    if *text == input_leaves[len].0 {
+                        len += 1;
+                    }
+                    if len == n {
+                        printed = Some(i + 1 - n);
+                        break;
+                    }
+                }
+
+                let locations = output_leaves
+                    .iter()
+                    .enumerate()
+                    .map(|(i, leaf)| {
+                        leaf.as_ref().map(|_| match printed {
+                            Some(start) if i >= start && i < start + n => input_leaves[i - start].1,
+                            _ => first,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                respan_tokens(output, &mut locations.into_iter())
+            }
+
+            // Visits every token in order, with a group before its contents.
+            // Invisible groups are skipped since they don't survive printing.
+            fn flatten(tokens: TokenStream, visit: &mut dyn FnMut(&TokenTree)) {
+                for token in tokens {
+                    if let TokenTree::Group(ref group) = token {
+                        if group.delimiter() != Delimiter::None {
+                            visit(&token);
+                        }
+                        flatten(group.stream(), visit);
+                    } else {
+                        visit(&token);
+                    }
+                }
+            }
+
+            fn leaf_text(token: &TokenTree) -> String {
+                match token {
+                    TokenTree::Group(group) => match group.delimiter() {
+                        Delimiter::Parenthesis => "(".to_owned(),
+                        Delimiter::Bracket => "[".to_owned(),
+                        Delimiter::Brace => "{".to_owned(),
+                        Delimiter::None => String::new(),
+                    },
+                    token => token.to_string(),
+                }
+            }
+
+            struct Location {
+                line: usize,
+                column: usize,
+                end_line: usize,
+                end_column: usize,
+                file: String,
+            }
+
+            #[allow(clippy::incompatible_msrv)]
+            impl Location {
+                fn of(span: Span) -> Self {
+                    Location {
+                        line: span.line(),
+                        column: span.column(),
+                        end_line: span.end().line(),
+                        end_column: span.end().column(),
+                        file: span.file(),
+                    }
+                }
 
-            let mut iter = input.into_iter();
-            iter.next().unwrap(); // `enum`
-            iter.next().unwrap(); // `ProcMacroHack`
-            iter.next().unwrap(); // `#`
-            iter.next().unwrap(); // `[allow(dead_code)]`
+                // Span::file allocates, so it is compared last.
+                fn is_at(&self, span: Span) -> bool {
+                    span.line() == self.line
+                        && span.column() == self.column
+                        && span.end().line() == self.end_line
+                        && span.end().column() == self.end_column
+                        && span.file() == self.file
+                }
+            }
+
+            #[allow(clippy::incompatible_msrv)]
+            fn respan_tokens(
+                output: TokenStream,
+                locations: &mut dyn Iterator<Item = Option<Span>>,
+            ) -> TokenStream {
+                output
+                    .into_iter()
+                    .map(|mut token| {
+                        let visited = match token {
+                            TokenTree::Group(ref group) => group.delimiter() != Delimiter::None,
+                            _ => true,
+                        };
+                        if visited {
+                            if let Some(Some(location)) = locations.next() {
+                                let span = token.span().located_at(location);
+                                token.set_span(span);
+                            }
+                        }
+                        if let TokenTree::Group(ref group) = token {
+                            let stream = respan_tokens(group.stream(), locations);
+                            let mut respanned = Group::new(group.delimiter(), stream);
+                            respanned.set_span(group.span());
+                            token = TokenTree::Group(respanned);
+                        }
+                        token
+                    })
+                    .collect()
+            }
+        }
+    };
+
+    // The dumps, and the pretty printer behind them, are only pasted into an
+    // implementation crate built while PROC_MACRO_HACK_DEBUG is set. build.rs
+    // reruns when the variable changes, which rebuilds every such crate.
+    let debug = debug_enabled();
+    let debug_name = TokenStream::from(TokenTree::Literal(Literal::string(
+        &unraw(&name).to_string(),
+    )));
+    let debug_helpers = if debug {
+        let source = include_str!("debug.rs");
+        let start = source.find(DEBUG_PASTE_MARKER).unwrap();
+        let source = source[start..].parse::<TokenStream>().unwrap();
+        quote! {
+            // The name of the macro and how many times it has been expanded in
+            // the crate being compiled.
+            pub fn debug_key() -> String {
+                use std::sync::atomic::{AtomicUsize, Ordering};
+
+                static COUNTER: AtomicUsize = AtomicUsize::new(0);
+                let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+                format!("{}.{}", #debug_name, id)
+            }
+
+            #source
+        }
+    } else {
+        TokenStream::new()
+    };
+
+    let helpers = quote! {
+        pub struct EnumHack {
+            pub name: Ident,
+            pub variant: String,
+            pub input: TokenStream,
+            pub protocol: u32,
+            pub version: Option<String>,
+        }
+
+        // #[allow(dead_code)]
+        // enum ProcMacroHack {
+        //     Value = (stringify! { ... }, 1, "0.5.20").1,
+        // }
+        //
+        // Declaration crates from before the protocol version was introduced
+        // emit `(stringify! { ... }, 0).1` instead.
+        pub fn parse_enum_hack(input: TokenStream) -> Result<EnumHack, String> {
+            let mut iter = input.into_iter().peekable();
+            while let Some(TokenTree::Punct(punct)) = iter.peek() {
+                if punct.as_char() != '#' {
+                    break;
+                }
+                iter.next();
+                expect_group(iter.next(), Delimiter::Bracket, "attribute")?;
+            }
+            expect_ident(iter.next(), "enum")?;
+            let name = match iter.next() {
+                Some(TokenTree::Ident(name)) => name,
+                token => return Err(mismatch(token, "name of enum")),
+            };
 
-            let mut braces = match iter.next().unwrap() {
-                #pub_name::TokenTree::Group(group) => group.stream().into_iter(),
-                _ => unimplemented!(),
+            let mut braces = expect_group(iter.next(), Delimiter::Brace, "`{`")?;
+            let variant = match braces.next() {
+                Some(TokenTree::Ident(variant)) => variant.to_string(),
+                token => return Err(mismatch(token, "name of variant")),
             };
-            let variant = braces.next().unwrap(); // `Value` or `Nested`
-            let varname = variant.to_string();
-            let support_nested = varname.starts_with("Nested");
-            braces.next().unwrap(); // `=`
-
-            let mut parens = match braces.next().unwrap() {
-                #pub_name::TokenTree::Group(group) => group.stream().into_iter(),
-                _ => unimplemented!(),
+            expect_punct(braces.next(), '=')?;
+
+            let mut parens = expect_group(braces.next(), Delimiter::Parenthesis, "`(`")?;
+            expect_ident(parens.next(), "stringify")?;
+            expect_punct(parens.next(), '!')?;
+            let input = match parens.next() {
+                Some(TokenTree::Group(group)) => group.stream(),
+                token => return Err(mismatch(token, "macro input")),
             };
-            parens.next().unwrap(); // `stringify`
-            parens.next().unwrap(); // `!`
 
-            let inner = match parens.next().unwrap() {
-                #pub_name::TokenTree::Group(group) => group.stream(),
-                _ => unimplemented!(),
+            expect_punct(parens.next(), ',')?;
+            let protocol = match parens.next() {
+                Some(TokenTree::Literal(ref lit)) if lit.to_string().parse::<u32>().is_ok() => {
+                    lit.to_string().parse().unwrap()
+                }
+                token => return Err(mismatch(token, "protocol version")),
             };
+            let version = match parens.next() {
+                None => None,
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == ',' => match parens.next() {
+                    Some(TokenTree::Literal(lit)) => Some(lit.to_string().trim_matches('"').to_owned()),
+                    token => return Err(mismatch(token, "proc-macro-hack version")),
+                },
+                token => return Err(mismatch(token, "`,`")),
+            };
+
+            Ok(EnumHack {
+                name,
+                variant,
+                input,
+                protocol,
+                version,
+            })
+        }
 
-            let output: #pub_name::TokenStream = #name(inner.clone());
+        fn expect_ident(token: Option<TokenTree>, expected: &str) -> Result<(), String> {
+            match token {
+                Some(TokenTree::Ident(ref ident)) if ident.to_string() == expected => Ok(()),
+                token => Err(mismatch(token, &format!("`{}`", expected))),
+            }
+        }
 
-            fn count_bangs(input: #pub_name::TokenStream) -> usize {
-                let mut count = 0;
-                for token in input {
-                    match token {
-                        #pub_name::TokenTree::Punct(punct) => {
-                            if punct.as_char() == '!' {
-                                count += 1;
-                            }
+        fn expect_punct(token: Option<TokenTree>, expected: char) -> Result<(), String> {
+            match token {
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == expected => Ok(()),
+                token => Err(mismatch(token, &format!("`{}`", expected))),
+            }
+        }
+
+        fn expect_group(
+            token: Option<TokenTree>,
+            delimiter: Delimiter,
+            expected: &str,
+        ) -> Result<token_stream::IntoIter, String> {
+            match token {
+                Some(TokenTree::Group(ref group)) if group.delimiter() == delimiter => {
+                    Ok(group.stream().into_iter())
+                }
+                token => Err(mismatch(token, expected)),
+            }
+        }
+
+        fn mismatch(token: Option<TokenTree>, expected: &str) -> String {
+            match token {
+                Some(token) => format!("expected {}, found `{}`", expected, token),
+                None => format!("expected {}, found end of input", expected),
+            }
+        }
+
+        // compile_error! { "..." }
+        pub fn compile_error(message: &str) -> TokenStream {
+            use std::iter::FromIterator;
+
+            TokenStream::from_iter(vec![
+                TokenTree::Ident(Ident::new("compile_error", Span::call_site())),
+                TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+                TokenTree::Group(Group::new(
+                    Delimiter::Brace,
+                    TokenStream::from(TokenTree::Literal(Literal::string(message))),
+                )),
+            ])
+        }
+
+        pub fn count_bangs(input: TokenStream) -> usize {
+            let mut count = 0;
+            for token in input {
+                match token {
+                    TokenTree::Punct(punct) => {
+                        if punct.as_char() == '!' {
+                            count += 1;
+                        }
+                    }
+                    TokenTree::Group(group) => {
+                        count += count_bangs(group.stream());
+                    }
+                    _ => {}
+                }
+            }
+            count
+        }
+
+        // Counts macro calls of the form `name!(...)`, `name![...]` or
+        // `name!{...}`, matching proc_macro_nested::dispatch_calls. That is a
+        // macro_rules macro, to which an invisible group is a single opaque
+        // token: it is never the body of a call and is not looked into.
+        pub fn count_calls(input: TokenStream) -> usize {
+            let mut count = 0;
+            let mut name: Option<Ident> = None;
+            let mut bang = false;
+            for token in input {
+                match token {
+                    TokenTree::Ident(ident) => {
+                        name = Some(ident);
+                        bang = false;
+                    }
+                    TokenTree::Punct(punct) => {
+                        bang = name.is_some()
+                            && !bang
+                            && punct.as_char() == '!'
+                            && punct.spacing() == Spacing::Alone;
+                        if !bang {
+                            name = None;
                         }
-                        #pub_name::TokenTree::Group(group) => {
-                            count += count_bangs(group.stream());
+                    }
+                    TokenTree::Group(ref group) if group.delimiter() == Delimiter::None => {
+                        name = None;
+                        bang = false;
+                    }
+                    TokenTree::Group(group) => {
+                        if bang && !name.as_ref().map_or(true, is_keyword) {
+                            count += 1;
                         }
-                        _ => {}
+                        count += count_calls(group.stream());
+                        name = None;
+                        bang = false;
                     }
+                    TokenTree::Literal(_) => {
+                        name = None;
+                        bang = false;
+                    }
+                }
+            }
+            count
+        }
+
+        // Keywords that may be directly followed by a unary `!` expression.
+        fn is_keyword(ident: &Ident) -> bool {
+            match ident.to_string().as_str() {
+                "box" | "break" | "if" | "in" | "match" | "return" | "while" | "yield" => true,
+                _ => false,
+            }
+        }
+
+        #respan
+
+        // args => input
+        #[allow(dead_code)]
+        pub fn split_args(input: TokenStream) -> Option<(TokenStream, TokenStream)> {
+            let mut args = Vec::new();
+            let mut input = input.into_iter();
+            while let Some(token) = input.next() {
+                if let TokenTree::Punct(ref punct) = token {
+                    if punct.as_char() == '>' {
+                        if let Some(TokenTree::Punct(ref prev)) = args.last() {
+                            if prev.as_char() == '=' && prev.spacing() == Spacing::Joint {
+                                args.pop();
+                                return Some((args.into_iter().collect(), input.collect()));
+                            }
+                        }
+                    }
+                }
+                args.push(token);
+            }
+            None
+        }
+
+        #debug_helpers
+
+        // #[deprecated(note = "...")]
+        // #[allow(non_upper_case_globals)]
+        // const proc_macro_hack_m_warning_0: () = ();
+        //
+        // #[allow(dead_code, non_upper_case_globals)]
+        // const _proc_macro_hack_m_warning_0: () = proc_macro_hack_m_warning_0;
+        #[allow(dead_code)]
+        pub fn warning(span: Span, message: &str) -> TokenStream {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let items = format!(
+                "#[deprecated(note = {note})] #[allow(non_upper_case_globals)] const {name}_{id}: () = (); #[allow(dead_code, non_upper_case_globals)] const _{name}_{id}: () = {name}_{id};",
+                note = Literal::string(message),
+                name = concat!("proc_macro_hack_", stringify!(#name), "_warning"),
+                id = id,
+            );
+            set_spans(items.parse().unwrap(), span)
+        }
+
+        fn set_spans(tokens: TokenStream, span: Span) -> TokenStream {
+            tokens
+                .into_iter()
+                .map(|mut token| {
+                    if let TokenTree::Group(ref group) = token {
+                        let stream = set_spans(group.stream(), span);
+                        token = TokenTree::Group(Group::new(group.delimiter(), stream));
+                    }
+                    token.set_span(span);
+                    token
+                })
+                .collect()
+        }
+    };
+
+    let derive = quote! {
+        use std::iter::FromIterator;
+
+        let hack = match #pub_name::parse_enum_hack(input) {
+            Ok(hack) => hack,
+            Err(mismatch) => {
+                return #pub_name::compile_error(&format!(
+                    "unexpected input to the hidden derive of proc-macro-hack macro `{}`: {}; the declaration crate was likely built with a version of proc-macro-hack that is incompatible with {}, used by the implementation crate",
+                    stringify!(#name),
+                    mismatch,
+                    #version,
+                ));
+            }
+        };
+
+        if hack.protocol > #protocol {
+            return #pub_name::compile_error(&format!(
+                "proc-macro-hack macro `{}` is declared using proc-macro-hack {} (protocol version {}) but implemented using proc-macro-hack {} (protocol version {}); upgrade proc-macro-hack in the implementation crate",
+                stringify!(#name),
+                hack.version.as_ref().map_or("<unknown>", String::as_str),
+                hack.protocol,
+                #version,
+                #protocol,
+            ));
+        }
+
+        // The error takes the place of the output, so that the macro which the
+        // call expands to is still defined and nothing else is reported.
+        let mismatch = if (hack.variant == "Attribute") != #takes_args {
+            Some(#pub_name::compile_error(&if #takes_args {
+                format!(
+                    "proc-macro-hack macro `{}` takes arguments, so its re-export needs #[proc_macro_hack(attribute)]",
+                    stringify!(#name),
+                )
+            } else {
+                format!(
+                    "proc-macro-hack macro `{}` does not take arguments, so its re-export can't use #[proc_macro_hack(attribute)]",
+                    stringify!(#name),
+                )
+            }))
+        } else {
+            None
+        };
+
+        // Items are emitted right next to the enum.
+        if hack.variant == "Items" {
+            return match mismatch {
+                Some(error) => error,
+                None => {
+                    let input = hack.input.clone();
+                    #pub_name::respan(#call, &hack.input)
                 }
-                count
-            }
-
-            // macro_rules! proc_macro_call {
-            //     () => { #output }
-            // }
-            #pub_name::TokenStream::from_iter(vec![
-                #pub_name::TokenTree::Ident(
-                    #pub_name::Ident::new("macro_rules", #pub_name::Span::call_site()),
-                ),
-                #pub_name::TokenTree::Punct(
-                    #pub_name::Punct::new('!', #pub_name::Spacing::Alone),
-                ),
-                #pub_name::TokenTree::Ident(
-                    #pub_name::Ident::new(
-                        &if support_nested {
-                            let extra_bangs = if varname == "Nested" {
-                                0
-                            } else {
-                                varname["Nested".len()..].parse().unwrap()
-                            };
-                            format!("proc_macro_call_{}", extra_bangs + count_bangs(inner))
-                        } else {
-                            String::from("proc_macro_call")
-                        },
-                        #pub_name::Span::call_site(),
+            };
+        }
+
+        let macro_name = if hack.variant == "Value" || hack.variant == "Attribute" {
+            #pub_name::Ident::new("proc_macro_call", #pub_name::Span::call_site())
+        } else if hack.variant == "Pattern" || hack.variant == "Type" {
+            hack.name
+        } else if hack.variant.starts_with("Calls") || hack.variant.starts_with("Nested") {
+            // Declaration crates older than protocol 3 dispatch on every `!`.
+            let (prefix, count): (&str, fn(#pub_name::TokenStream) -> usize) =
+                if hack.variant.starts_with("Calls") {
+                    ("Calls", #pub_name::count_calls)
+                } else {
+                    ("Nested", #pub_name::count_bangs)
+                };
+            let extra_bangs = if hack.variant == prefix {
+                Ok(0)
+            } else {
+                hack.variant[prefix.len()..].parse::<usize>()
+            };
+            match extra_bangs {
+                Ok(extra_bangs) => {
+                    let bangs = extra_bangs + count(hack.input.clone());
+                    let name = format!("proc_macro_call_{}", bangs);
+                    #pub_name::Ident::new(&name, #pub_name::Span::call_site())
+                }
+                Err(_) => {
+                    return #pub_name::compile_error(&format!(
+                        "unrecognized variant `{}` in input to the hidden derive of proc-macro-hack macro `{}`",
+                        hack.variant,
+                        stringify!(#name),
+                    ));
+                }
+            }
+        } else {
+            return #pub_name::compile_error(&format!(
+                "unrecognized variant `{}` in input to the hidden derive of proc-macro-hack macro `{}`, which is declared using proc-macro-hack {} but implemented using proc-macro-hack {}",
+                hack.variant,
+                stringify!(#name),
+                hack.version.as_ref().map_or("<unknown>", String::as_str),
+                #version,
+            ));
+        };
+
+        let output = match mismatch {
+            Some(error) => error,
+            None => {
+                let input = hack.input.clone();
+                #pub_name::respan(#call, &hack.input)
+            }
+        };
+
+        // macro_rules! proc_macro_call {
+        //     () => { #output }
+        // }
+        //
+        // or for patterns and types, a macro with the name of the enum.
+        #pub_name::TokenStream::from_iter(vec![
+            #pub_name::TokenTree::Ident(
+                #pub_name::Ident::new("macro_rules", #pub_name::Span::call_site()),
+            ),
+            #pub_name::TokenTree::Punct(
+                #pub_name::Punct::new('!', #pub_name::Spacing::Alone),
+            ),
+            #pub_name::TokenTree::Ident(macro_name),
+            #pub_name::TokenTree::Group(
+                #pub_name::Group::new(#pub_name::Delimiter::Brace, #pub_name::TokenStream::from_iter(vec![
+                    #pub_name::TokenTree::Group(
+                        #pub_name::Group::new(#pub_name::Delimiter::Parenthesis, #pub_name::TokenStream::new()),
                     ),
-                ),
-                #pub_name::TokenTree::Group(
-                    #pub_name::Group::new(#pub_name::Delimiter::Brace, #pub_name::TokenStream::from_iter(vec![
-                        #pub_name::TokenTree::Group(
-                            #pub_name::Group::new(#pub_name::Delimiter::Parenthesis, #pub_name::TokenStream::new()),
-                        ),
-                        #pub_name::TokenTree::Punct(
-                            #pub_name::Punct::new('=', #pub_name::Spacing::Joint),
-                        ),
-                        #pub_name::TokenTree::Punct(
-                            #pub_name::Punct::new('>', #pub_name::Spacing::Alone),
-                        ),
-                        #pub_name::TokenTree::Group(
-                            #pub_name::Group::new(#pub_name::Delimiter::Brace, output),
-                        ),
-                    ])),
-                ),
-            ])
+                    #pub_name::TokenTree::Punct(
+                        #pub_name::Punct::new('=', #pub_name::Spacing::Joint),
+                    ),
+                    #pub_name::TokenTree::Punct(
+                        #pub_name::Punct::new('>', #pub_name::Spacing::Alone),
+                    ),
+                    #pub_name::TokenTree::Group(
+                        #pub_name::Group::new(#pub_name::Delimiter::Brace, output),
+                    ),
+                ])),
+            ),
+        ])
+    };
+
+    let (hacked_body, native_body) = if debug {
+        (
+            quote! {
+                let key = #pub_name::debug_key();
+                #pub_name::debug_dump(&key, "enum", &input);
+                let output = (move || -> #pub_name::TokenStream { #derive })();
+                #pub_name::debug_dump(&key, "derive", &output);
+                output
+            },
+            quote! {
+                let key = #pub_name::debug_key();
+                let output = #call;
+                #pub_name::debug_dump(&key, "native", &output);
+                output
+            },
+        )
+    } else {
+        (
+            quote! {
+                (move || -> #pub_name::TokenStream { #derive })()
+            },
+            call,
+        )
+    };
+
+    quote! {
+        mod #pub_name {
+            extern crate proc_macro;
+            pub use self::proc_macro::*;
+
+            #helpers
+        }
+
+        #attrs
+        #[doc(hidden)]
+        #[proc_macro_derive(#hacked)]
+        pub fn #hacked(input: #pub_name::TokenStream) -> #pub_name::TokenStream {
+            #hacked_body
         }
 
         #attrs
         #[proc_macro]
         pub fn #pub_name(input: #pub_name::TokenStream) -> #pub_name::TokenStream {
-            #name(input)
+            #native_body
+        }
+
+        // Shadowed by the same macro from every later #[proc_macro_hack] in the
+        // module, which is fine since they all do the same thing.
+        #[allow(unused_macros)]
+        macro_rules! proc_macro_hack_warning {
+            ($span:expr, $message:expr $(,)*) => {
+                #pub_name::warning($span, $message)
+            };
         }
 
         fn #name #body
     }
 }
 
+// Highest protocol version understood by the derive in the implementation
+// crate. The declaration crate emits the lowest version that covers the shape
+// of its enum, so that unrelated changes don't require upgrading both crates.
+//
+// 1: Value and Nested variants
+// 2: Pattern, Type and Items variants
+// 3: Calls variant, counting only `name!(...)` macro calls. It is not called
+//    NestedCalls because derives from before protocol versions parse whatever
+//    follows `Nested` as a number, and would panic.
+// 4: Attribute variant, with the args before the input separated by `=>`
+const PROTOCOL_VERSION: u32 = 4;
+
+fn protocol_literal(protocol: u32) -> TokenStream {
+    TokenStream::from(TokenTree::Literal(Literal::u32_unsuffixed(protocol)))
+}
+
+fn version_literal() -> TokenStream {
+    TokenStream::from(TokenTree::Literal(Literal::string(env!(
+        "CARGO_PKG_VERSION"
+    ))))
+}
+
 fn pub_proc_macro_name(conceptual: &Ident) -> Ident {
     Ident::new(
         &format!("proc_macro_hack_{}", conceptual),
@@ -568,10 +1911,19 @@ fn call_site_macro_name(conceptual: &Ident) -> Ident {
     )
 }
 
+fn unique_items_macro_name(conceptual: &Ident) -> Ident {
+    Ident::new(
+        &format!("proc_macro_unique_items_{}", conceptual),
+        conceptual.span(),
+    )
+}
+
 fn dummy_name_for_export(export: &Export) -> String {
     let mut dummy = String::new();
-    let from = unraw(&export.from).to_string();
-    write!(dummy, "_{}{}", from.len(), from).unwrap();
+    for segment in &export.from.segments {
+        let segment = unraw(segment).to_string();
+        write!(dummy, "_{}{}", segment.len(), segment).unwrap();
+    }
     for m in &export.macros {
         let name = unraw(&m.name).to_string();
         write!(dummy, "_{}{}", name.len(), name).unwrap();
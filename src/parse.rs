@@ -1,5 +1,9 @@
+use crate::error;
 use crate::iter::{self, Iter, IterImpl};
-use crate::{Define, Error, Export, ExportArgs, FakeCallSite, Input, Macro, Visibility};
+use crate::{
+    unraw, Define, Error, Export, ExportArgs, FakeCallSite, Glob, Hygiene, Input, Macro, Path,
+    Position, Visibility,
+};
 use proc_macro::Delimiter::{Brace, Bracket, Parenthesis};
 use proc_macro::{Delimiter, Ident, Span, TokenStream, TokenTree};
 
@@ -8,7 +12,7 @@ pub(crate) fn parse_input(tokens: Iter) -> Result<Input, Error> {
     let vis = parse_visibility(tokens);
     let kw = parse_ident(tokens)?;
     if kw.to_string() == "use" {
-        parse_export(attrs, vis, tokens).map(Input::Export)
+        parse_export(attrs, vis, tokens)
     } else if kw.to_string() == "fn" {
         parse_define(attrs, vis, kw.span(), tokens).map(Input::Define)
     } else {
@@ -19,39 +23,89 @@ pub(crate) fn parse_input(tokens: Iter) -> Result<Input, Error> {
     }
 }
 
-fn parse_export(attrs: TokenStream, vis: Visibility, tokens: Iter) -> Result<Export, Error> {
-    let _ = parse_punct(tokens, ':');
-    let _ = parse_punct(tokens, ':');
-    let from = parse_ident(tokens)?;
+fn parse_export(attrs: TokenStream, vis: Visibility, tokens: Iter) -> Result<Input, Error> {
+    let mut from = Path {
+        global: false,
+        segments: Vec::new(),
+    };
+    if let Some(TokenTree::Punct(punct)) = tokens.peek() {
+        if punct.as_char() == ':' {
+            parse_punct(tokens, ':')?;
+            parse_punct(tokens, ':')?;
+            from.global = true;
+        }
+    }
+    from.segments.push(parse_ident(tokens)?);
     parse_punct(tokens, ':')?;
     parse_punct(tokens, ':')?;
 
     let mut macros = Vec::new();
-    match tokens.peek() {
-        Some(TokenTree::Group(group)) if group.delimiter() == Brace => {
-            let ref mut content = iter::new(group.stream());
-            loop {
-                macros.push(parse_macro(content)?);
-                if content.peek().is_none() {
-                    break;
+    loop {
+        match tokens.peek() {
+            Some(TokenTree::Punct(punct)) if punct.as_char() == '*' => {
+                tokens.next().unwrap();
+                parse_punct(tokens, ';')?;
+                check_restricted_path(&vis, &from)?;
+                return Ok(Input::Glob(Glob { attrs, vis, from }));
+            }
+            Some(TokenTree::Group(group)) if group.delimiter() == Brace => {
+                let ref mut content = iter::new(group.stream());
+                loop {
+                    macros.push(parse_macro(content)?);
+                    if content.peek().is_none() {
+                        break;
+                    }
+                    parse_punct(content, ',')?;
+                    if content.peek().is_none() {
+                        break;
+                    }
                 }
-                parse_punct(content, ',')?;
-                if content.peek().is_none() {
-                    break;
+                tokens.next().unwrap();
+                break;
+            }
+            _ => {
+                let segment = parse_ident(tokens)?;
+                match tokens.peek() {
+                    Some(TokenTree::Punct(punct)) if punct.as_char() == ':' => {
+                        parse_punct(tokens, ':')?;
+                        parse_punct(tokens, ':')?;
+                        from.segments.push(segment);
+                    }
+                    _ => {
+                        macros.push(parse_macro_rename(TokenStream::new(), segment, tokens)?);
+                        break;
+                    }
                 }
             }
-            tokens.next().unwrap();
         }
-        _ => macros.push(parse_macro(tokens)?),
     }
 
     parse_punct(tokens, ';')?;
-    Ok(Export {
+    check_restricted_path(&vis, &from)?;
+
+    Ok(Input::Export(Export {
         attrs,
         vis,
         from,
         macros,
-    })
+    }))
+}
+
+// The macro_rules of a restricted re-export names the path from whichever
+// module the macro is called in. Only `::` and `crate::` paths mean the same
+// thing everywhere in both editions.
+fn check_restricted_path(vis: &Visibility, from: &Path) -> Result<(), Error> {
+    if let Visibility::Restricted(..) = vis {
+        let first = &from.segments[0];
+        if !from.global && first.to_string() != "crate" {
+            return Err(Error::new(
+                first.span(),
+                "re-exports with restricted visibility must name the implementation crate by an absolute path",
+            )
+            .help("start the path with `::` or `crate::`"));
+        }
+    }
+    Ok(())
 }
 
 fn parse_punct(tokens: Iter, ch: char) -> Result<(), Error> {
@@ -73,19 +127,77 @@ fn parse_define(
     fn_token: Span,
     tokens: Iter,
 ) -> Result<Define, Error> {
-    if vis.is_none() {
-        return Err(Error::new(
-            fn_token,
-            "functions tagged with `#[proc_macro_hack]` must be `pub`",
-        ));
+    match vis {
+        Visibility::Public(_) => {}
+        Visibility::Restricted(..) | Visibility::Inherited => {
+            return Err(Error::new(
+                fn_token,
+                "functions tagged with `#[proc_macro_hack]` must be `pub`",
+            ));
+        }
     }
     let name = parse_ident(tokens)?;
-    let body = tokens.collect();
-    Ok(Define { attrs, name, body })
+    let body: TokenStream = tokens.collect();
+    let fallible = returns_result(&body);
+    let takes_args = count_params(&body) == 2;
+    Ok(Define {
+        attrs,
+        name,
+        body,
+        fallible,
+        takes_args,
+    })
+}
+
+// (args: TokenStream, input: TokenStream) -> ... { ... }
+fn count_params(body: &TokenStream) -> usize {
+    let params = match body.clone().into_iter().next() {
+        Some(TokenTree::Group(group)) => group.stream(),
+        _ => return 0,
+    };
+    let mut count = 0;
+    let mut empty = true;
+    for tt in params {
+        match tt {
+            TokenTree::Punct(ref punct) if punct.as_char() == ',' => empty = true,
+            _ => {
+                if empty {
+                    count += 1;
+                }
+                empty = false;
+            }
+        }
+    }
+    count
+}
+
+// (input: TokenStream) -> Result<TokenStream, Error> { ... }
+fn returns_result(body: &TokenStream) -> bool {
+    let mut return_type = body.clone().into_iter().skip_while(|tt| match tt {
+        TokenTree::Punct(punct) => punct.as_char() != '>',
+        _ => true,
+    });
+    return_type.next(); // `>` of the `->`
+
+    let mut last_ident = None;
+    for tt in return_type {
+        match tt {
+            TokenTree::Ident(ident) => last_ident = Some(ident.to_string()),
+            TokenTree::Punct(ref punct) if punct.as_char() == '<' => break,
+            TokenTree::Punct(_) => {}
+            TokenTree::Group(_) | TokenTree::Literal(_) => return false,
+        }
+    }
+    last_ident.map_or(false, |ident| ident == "Result")
 }
 
 fn parse_macro(tokens: Iter) -> Result<Macro, Error> {
+    let attrs = parse_attributes(tokens)?;
     let name = parse_ident(tokens)?;
+    parse_macro_rename(attrs, name, tokens)
+}
+
+fn parse_macro_rename(attrs: TokenStream, name: Ident, tokens: Iter) -> Result<Macro, Error> {
     let export_as = match tokens.peek() {
         Some(TokenTree::Ident(ident)) if ident.to_string() == "as" => {
             tokens.next().unwrap();
@@ -93,7 +205,12 @@ fn parse_macro(tokens: Iter) -> Result<Macro, Error> {
         }
         _ => name.clone(),
     };
-    Ok(Macro { name, export_as })
+    Ok(Macro {
+        attrs,
+        name,
+        export_as,
+        args: None,
+    })
 }
 
 fn parse_ident(tokens: Iter) -> Result<Ident, Error> {
@@ -124,6 +241,28 @@ fn parse_int(tokens: Iter) -> Result<u16, Span> {
     }
 }
 
+fn parse_hygiene(tokens: Iter) -> Result<Hygiene, Error> {
+    let lit = match tokens.next() {
+        Some(TokenTree::Literal(lit)) => lit,
+        tt => {
+            return Err(Error::new(
+                tt.as_ref().map_or_else(Span::call_site, TokenTree::span),
+                "expected string value for hygiene",
+            ));
+        }
+    };
+    match lit.to_string().as_str() {
+        "\"mixed_site\"" => Ok(Hygiene::MixedSite),
+        "\"call_site\"" => Ok(Hygiene::CallSite),
+        "\"def_site\"" => Err(Error::new(lit.span(), "def_site hygiene is not supported")
+            .note("Span::def_site is unstable")),
+        _ => Err(Error::new(
+            lit.span(),
+            "expected one of: \"mixed_site\", \"call_site\"",
+        )),
+    }
+}
+
 fn parse_group(tokens: Iter, delimiter: Delimiter) -> Result<IterImpl, Error> {
     match &tokens.next() {
         Some(TokenTree::Group(group)) if group.delimiter() == delimiter => {
@@ -137,15 +276,20 @@ fn parse_group(tokens: Iter, delimiter: Delimiter) -> Result<IterImpl, Error> {
 }
 
 fn parse_visibility(tokens: Iter) -> Visibility {
-    if let Some(TokenTree::Ident(ident)) = tokens.peek() {
-        if ident.to_string() == "pub" {
-            match tokens.next().unwrap() {
-                TokenTree::Ident(vis) => return Some(vis),
-                _ => unreachable!(),
-            }
-        }
+    let vis = match tokens.peek() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "pub" => match tokens.next() {
+            Some(TokenTree::Ident(vis)) => vis,
+            _ => unreachable!(),
+        },
+        _ => return Visibility::Inherited,
+    };
+    match tokens.peek() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Parenthesis => match tokens.next() {
+            Some(TokenTree::Group(restriction)) => Visibility::Restricted(vis, restriction),
+            _ => unreachable!(),
+        },
+        _ => Visibility::Public(vis),
     }
-    None
 }
 
 fn parse_attributes(tokens: Iter) -> Result<TokenStream, Error> {
@@ -166,46 +310,374 @@ fn parse_attributes(tokens: Iter) -> Result<TokenStream, Error> {
     Ok(attrs)
 }
 
-pub(crate) fn parse_export_args(tokens: Iter) -> Result<ExportArgs, Error> {
+pub(crate) fn parse_export_args(tokens: Iter, macros: &mut [Macro]) -> Result<ExportArgs, Error> {
     let mut args = ExportArgs {
         support_nested: false,
         internal_macro_calls: 0,
-        fake_call_site: false,
+        hygiene: Hygiene::MixedSite,
         only_hack_old_rustc: false,
+        always_hack: None,
+        position: None,
+        items: None,
+        attribute: None,
     };
+    let mut macro_options = Vec::new();
+    let mut errors = None;
+    let mut seen = Vec::new();
 
     while let Some(tt) = tokens.next() {
-        match &tt {
-            TokenTree::Ident(ident) if ident.to_string() == "support_nested" => {
-                args.support_nested = true;
+        let result = match &tt {
+            TokenTree::Ident(ident) if ident.to_string() == "for" => {
+                parse_macro_attrs(tokens, macros, &mut macro_options)
             }
-            TokenTree::Ident(ident) if ident.to_string() == "internal_macro_calls" => {
-                parse_punct(tokens, '=')?;
-                let calls = parse_int(tokens).map_err(|span| {
-                    Error::new(span, "expected integer value for internal_macro_calls")
-                })?;
-                args.internal_macro_calls = calls;
-            }
-            TokenTree::Ident(ident) if ident.to_string() == "fake_call_site" => {
-                args.fake_call_site = true;
+            _ => parse_export_arg(tt, tokens, &mut args, &mut seen),
+        };
+        if let Err(err) = result.and_then(|()| parse_separator(tokens)) {
+            error::combine(&mut errors, err);
+            skip_arg(tokens);
+        }
+    }
+
+    // Per-macro options are layered on top of the ones that apply to the whole
+    // re-export, regardless of the order they were written in.
+    for (i, options) in macro_options {
+        let mut macro_args = macros[i].args.take().unwrap_or_else(|| args.clone());
+        let only_hack_old_rustc = macro_args.only_hack_old_rustc;
+        let ref mut options = iter::new(options);
+        let mut seen = Vec::new();
+        while let Some(tt) = options.next() {
+            let result = parse_export_arg(tt, options, &mut macro_args, &mut seen);
+            if let Err(err) = result.and_then(|()| parse_separator(options)) {
+                error::combine(&mut errors, err);
+                skip_arg(options);
             }
-            TokenTree::Ident(ident) if ident.to_string() == "only_hack_old_rustc" => {
-                args.only_hack_old_rustc = true;
+        }
+        // Choosing between the hack and native expansion for one macro
+        // overrides the choice made for the whole re-export.
+        let own = |arg: &str| seen.iter().any(|seen| seen == arg);
+        if own("always_hack") && !own("only_hack_old_rustc") {
+            if !only_hack_old_rustc {
+                let span = macro_args.always_hack.unwrap();
+                error::combine(&mut errors, always_hack_without_effect(span));
             }
-            _ => {
+            macro_args.only_hack_old_rustc = false;
+        } else if own("only_hack_old_rustc") && !own("always_hack") {
+            macro_args.always_hack = None;
+        }
+        macros[i].args = Some(macro_args);
+    }
+
+    if let Some(span) = args.always_hack {
+        if !args.only_hack_old_rustc {
+            error::combine(&mut errors, always_hack_without_effect(span));
+        }
+    }
+
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+
+    validate_export_args(&args)?;
+    for mac in macros.iter() {
+        if let Some(args) = &mac.args {
+            validate_export_args(args)?;
+        }
+    }
+
+    Ok(args)
+}
+
+// Macros are expanded through the hack unless the re-export opts in to native
+// expansion, so always_hack only means something as an exception to that.
+fn always_hack_without_effect(span: Span) -> Error {
+    Error::new(
+        span,
+        "`always_hack` has no effect without `only_hack_old_rustc` on the re-export",
+    )
+    .help(
+        "macros are expanded through the hack by default; `always_hack` goes in a `for` block to keep one macro of an `only_hack_old_rustc` re-export on the hack",
+    )
+}
+
+fn validate_export_args(args: &ExportArgs) -> Result<(), Error> {
+    if let Some(span) = args.always_hack {
+        if args.only_hack_old_rustc {
+            return Err(Error::new(
+                span,
+                "`always_hack` cannot be combined with `only_hack_old_rustc`",
+            ));
+        }
+    }
+    if let Some((position, span)) = args.position {
+        let conflict = if args.support_nested {
+            Some("support_nested")
+        } else if args.hygiene == Hygiene::CallSite {
+            Some("hygiene = \"call_site\"")
+        } else if args.only_hack_old_rustc {
+            Some("only_hack_old_rustc")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::new(
+                span,
+                format!(
+                    "`{}` cannot be combined with `{}`",
+                    position.arg(),
+                    conflict
+                ),
+            ));
+        }
+    }
+    if let Some(span) = args.items {
+        let conflict = if let Some((position, _)) = args.position {
+            Some(position.arg())
+        } else if args.support_nested {
+            Some("support_nested")
+        } else if args.hygiene == Hygiene::CallSite {
+            Some("hygiene = \"call_site\"")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::new(
+                span,
+                format!("`items` cannot be combined with `{}`", conflict),
+            ));
+        }
+    }
+    if let Some(span) = args.attribute {
+        let conflict = if let Some((position, _)) = args.position {
+            Some(position.arg())
+        } else if args.items.is_some() {
+            Some("items")
+        } else if args.support_nested {
+            Some("support_nested")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::new(
+                span,
+                format!("`attribute` cannot be combined with `{}`", conflict),
+            ));
+        }
+    }
+    Ok(())
+}
+
+const EXPORT_ARGS: &[&str] = &[
+    "support_nested",
+    "internal_macro_calls",
+    "fake_call_site",
+    "hygiene",
+    "only_hack_old_rustc",
+    "always_hack",
+    "pattern",
+    "type_position",
+    "items",
+    "attribute",
+];
+
+fn parse_export_arg(
+    tt: TokenTree,
+    tokens: Iter,
+    args: &mut ExportArgs,
+    seen: &mut Vec<String>,
+) -> Result<(), Error> {
+    if let TokenTree::Ident(ident) = &tt {
+        let name = ident.to_string();
+        if EXPORT_ARGS.contains(&name.as_str()) {
+            if seen.contains(&name) {
                 return Err(Error::new(
-                    tt.span(),
-                    "expected one of: `support_nested`, `internal_macro_calls`, `fake_call_site`, `only_hack_old_rustc`",
+                    ident.span(),
+                    format!("duplicate argument `{}`", name),
                 ));
             }
+            seen.push(name);
         }
-        if tokens.peek().is_none() {
-            break;
+    }
+
+    match &tt {
+        TokenTree::Ident(ident) if ident.to_string() == "support_nested" => {
+            args.support_nested = true;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "internal_macro_calls" => {
+            parse_punct(tokens, '=')?;
+            let calls = parse_int(tokens).map_err(|span| {
+                Error::new(span, "expected integer value for internal_macro_calls")
+            })?;
+            args.internal_macro_calls = calls;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "fake_call_site" => {
+            args.hygiene = Hygiene::CallSite;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "hygiene" => {
+            parse_punct(tokens, '=')?;
+            args.hygiene = parse_hygiene(tokens)?;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "only_hack_old_rustc" => {
+            args.only_hack_old_rustc = true;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "always_hack" => {
+            args.always_hack = Some(ident.span());
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "pattern" => {
+            set_position(args, Position::Pattern, ident.span())?;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "type_position" => {
+            set_position(args, Position::Type, ident.span())?;
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "items" => {
+            args.items = Some(ident.span());
+        }
+        TokenTree::Ident(ident) if ident.to_string() == "attribute" => {
+            args.attribute = Some(ident.span());
+        }
+        _ => {
+            let expected = EXPORT_ARGS
+                .iter()
+                .map(|arg| format!("`{}`", arg))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let err = Error::new(tt.span(), format!("expected one of: {}", expected));
+            return Err(match &tt {
+                TokenTree::Ident(ident) => match similar_arg(&ident.to_string()) {
+                    Some(arg) => err.help(format!("did you mean `{}`?", arg)),
+                    None => err,
+                },
+                _ => err,
+            });
         }
-        parse_punct(tokens, ',')?;
     }
+    Ok(())
+}
 
-    Ok(args)
+// The closest known argument within a couple of typos, by edit distance.
+fn similar_arg(name: &str) -> Option<&'static str> {
+    let max_distance = (name.len() / 3).max(2);
+    EXPORT_ARGS
+        .iter()
+        .map(|arg| (edit_distance(name, arg), *arg))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, arg)| arg)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Either the end of the arguments or a `,` before the next one.
+fn parse_separator(tokens: Iter) -> Result<(), Error> {
+    if tokens.peek().is_none() {
+        return Ok(());
+    }
+    parse_punct(tokens, ',')
+}
+
+// Skips the rest of a malformed argument, so that the arguments after it are
+// still checked.
+fn skip_arg(tokens: Iter) {
+    for tt in tokens {
+        if let TokenTree::Punct(punct) = &tt {
+            if punct.as_char() == ',' {
+                break;
+            }
+        }
+    }
+}
+
+fn set_position(args: &mut ExportArgs, position: Position, span: Span) -> Result<(), Error> {
+    if let Some((previous, _)) = args.position.filter(|(previous, _)| *previous != position) {
+        return Err(Error::new(
+            span,
+            format!(
+                "`{}` cannot be combined with `{}`",
+                position.arg(),
+                previous.arg()
+            ),
+        ));
+    }
+    args.position = Some((position, span));
+    Ok(())
+}
+
+// for m2 { #[doc = "..."] #[proc_macro_hack(support_nested)] }
+fn parse_macro_attrs(
+    tokens: Iter,
+    macros: &mut [Macro],
+    macro_options: &mut Vec<(usize, TokenStream)>,
+) -> Result<(), Error> {
+    let name = parse_ident(tokens)?;
+    let ref mut content = parse_group(tokens, Brace)?;
+    let attrs = parse_attributes(content)?;
+    if let Some(tt) = content.next() {
+        return Err(Error::new(tt.span(), "expected attribute"));
+    }
+
+    let unraw_name = unraw(&name).to_string();
+    let i = match macros
+        .iter()
+        .position(|mac| unraw(&mac.export_as).to_string() == unraw_name)
+    {
+        Some(i) => i,
+        None => {
+            return Err(Error::new(
+                name.span(),
+                format!("no macro named `{}` in this re-export", name),
+            ));
+        }
+    };
+
+    let ref mut attrs = iter::new(attrs);
+    while let Some(pound) = attrs.next() {
+        let attr = match attrs.next() {
+            Some(TokenTree::Group(attr)) => attr,
+            _ => unreachable!(),
+        };
+        let ref mut content = iter::new(attr.stream());
+        match content.peek() {
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "proc_macro_hack" => {
+                content.next().unwrap();
+                let options = parse_group(content, Parenthesis)?;
+                macro_options.push((i, options.collect()));
+            }
+            _ => macros[i].attrs.extend(vec![pound, TokenTree::Group(attr)]),
+        }
+    }
+    Ok(())
+}
+
+// add_one, add_x, repeat
+pub(crate) fn parse_manifest(tokens: Iter) -> Result<Vec<Ident>, Error> {
+    let mut names: Vec<Ident> = Vec::new();
+    while tokens.peek().is_some() {
+        let name = parse_ident(tokens)?;
+        let string = name.to_string();
+        if names.iter().any(|prev| prev.to_string() == string) {
+            return Err(Error::new(
+                name.span(),
+                format!("duplicate macro `{}` in manifest", string),
+            ));
+        }
+        names.push(name);
+        if tokens.peek().is_some() {
+            parse_punct(tokens, ',')?;
+        }
+    }
+    Ok(names)
 }
 
 pub(crate) fn parse_define_args(tokens: Iter) -> Result<(), Error> {
@@ -1,3 +1,4 @@
+use crate::{Path, Visibility};
 use proc_macro::{Ident, TokenStream, TokenTree};
 use std::iter;
 
@@ -99,6 +100,30 @@ impl Tokens for TokenStream {
     }
 }
 
+impl Tokens for Path {
+    fn extend(tokens: &mut TokenStream, var: &Self) {
+        for (i, segment) in var.segments.iter().enumerate() {
+            if i > 0 || var.global {
+                tokens.extend(quote!(::));
+            }
+            tokens.extend(iter::once(TokenTree::Ident(segment.clone())));
+        }
+    }
+}
+
+impl Tokens for Visibility {
+    fn extend(tokens: &mut TokenStream, var: &Self) {
+        match var {
+            Visibility::Public(vis) => Tokens::extend(tokens, vis),
+            Visibility::Restricted(vis, restriction) => {
+                Tokens::extend(tokens, vis);
+                tokens.extend(iter::once(TokenTree::Group(restriction.clone())));
+            }
+            Visibility::Inherited => {}
+        }
+    }
+}
+
 impl<T: Tokens> Tokens for Option<T> {
     fn extend(tokens: &mut TokenStream, var: &Self) {
         if let Some(var) = var {
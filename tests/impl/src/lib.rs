@@ -0,0 +1,132 @@
+// Macros for tests/pass and tests/ui, kept out of demo-hack-impl so that the
+// demo stays a minimal example.
+
+#![allow(
+    // Clippy bug: https://github.com/rust-lang/rust-clippy/issues/7422
+    clippy::nonstandard_macro_braces,
+)]
+
+use proc_macro::{Delimiter, Group, TokenStream, TokenTree};
+use proc_macro_hack::proc_macro_hack;
+use quote::quote;
+use syn::{parse_macro_input, Expr};
+
+#[proc_macro_hack]
+pub fn add_one(input: TokenStream) -> TokenStream {
+    let expr = parse_macro_input!(input as Expr);
+    TokenStream::from(quote! {
+        1 + (#expr)
+    })
+}
+
+/// Like `add_one!` but builds its output as a string, the way many older
+/// macros do, so none of the tokens keep the span they had in the input.
+#[proc_macro_hack]
+pub fn add_one_str(input: TokenStream) -> TokenStream {
+    format!("1 + ({})", input).parse().unwrap()
+}
+
+/// Like `add_one!` but panics on overflow, through a call to `panic!` in its
+/// output.
+#[proc_macro_hack]
+pub fn add_one_checked(input: TokenStream) -> TokenStream {
+    let expr = parse_macro_input!(input as Expr);
+    TokenStream::from(quote! {
+        match (#expr).checked_add(1) {
+            Some(sum) => sum,
+            None => panic!("overflow in add_one_checked!"),
+        }
+    })
+}
+
+/// Like `add_one!` but returns an error rather than calling
+/// `parse_macro_input!`.
+#[proc_macro_hack]
+pub fn add_one_result(input: TokenStream) -> syn::Result<TokenStream> {
+    let expr: Expr = syn::parse(input)?;
+    Ok(TokenStream::from(quote! {
+        1 + (#expr)
+    }))
+}
+
+/// Refers to a local variable `x` of the caller which is not named in the
+/// macro input. This only compiles when the macro is expanded natively or with
+/// `fake_call_site`.
+#[proc_macro_hack]
+pub fn add_x(input: TokenStream) -> TokenStream {
+    let expr = parse_macro_input!(input as Expr);
+    TokenStream::from(quote! {
+        x + (#expr)
+    })
+}
+
+/// Like `add_one!` but also accepts the old `add_one_compat!(= x)` syntax,
+/// with a warning.
+#[proc_macro_hack]
+pub fn add_one_compat(input: TokenStream) -> TokenStream {
+    let mut output = TokenStream::new();
+
+    let mut input = input.into_iter().peekable();
+    if let Some(TokenTree::Punct(punct)) = input.peek() {
+        if punct.as_char() == '=' {
+            let warning = "the `=` is no longer needed in add_one_compat!";
+            output.extend(proc_macro_hack_warning!(punct.span(), warning));
+            input.next();
+        }
+    }
+
+    let input = input.collect::<TokenStream>();
+    let expr = parse_macro_input!(input as Expr);
+    output.extend(TokenStream::from(quote! {
+        1 + (#expr)
+    }));
+    TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, output)))
+}
+
+/// Wraps its comma-separated input in parentheses, which makes a tuple
+/// pattern or a tuple type depending on where the output is used.
+#[proc_macro_hack]
+pub fn tuple(input: TokenStream) -> TokenStream {
+    TokenStream::from(TokenTree::Group(Group::new(Delimiter::Parenthesis, input)))
+}
+
+/// Called as `define_consts!(A = 1, B = 2)`, defines a `usize` constant for
+/// each name.
+#[proc_macro_hack]
+pub fn define_consts(input: TokenStream) -> TokenStream {
+    let mut output = TokenStream::new();
+    let mut tokens = input.into_iter();
+    while let Some(name) = tokens.next() {
+        let _eq = tokens.next();
+        let value = tokens.next();
+        let _comma = tokens.next();
+        let item = format!("pub const {}: usize = {};", name, value.unwrap());
+        output.extend(item.parse::<TokenStream>().unwrap());
+    }
+    output
+}
+
+/// Called as `repeat!(n => expr)`, makes an array of `n` copies of `expr`.
+#[proc_macro_hack]
+pub fn repeat(args: TokenStream, input: TokenStream) -> syn::Result<TokenStream> {
+    let n: syn::LitInt = syn::parse(args)?;
+    let n: usize = n.base10_parse()?;
+    let expr: Expr = syn::parse(input)?;
+    let exprs = vec![expr; n];
+    Ok(TokenStream::from(quote! {
+        [#(#exprs),*]
+    }))
+}
+
+// Lets the declaration crate re-export every macro above with a glob.
+proc_macro_hack::manifest!(
+    add_one,
+    add_one_checked,
+    add_one_result,
+    add_one_str,
+    add_x,
+    add_one_compat,
+    tuple,
+    define_consts,
+    repeat
+);
@@ -0,0 +1,19 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(attribute)]
+pub use test_hack_impl::repeat as repeat_hack;
+
+#[proc_macro_hack(attribute, only_hack_old_rustc)]
+pub use test_hack_impl::repeat as repeat_native;
+
+mod within {
+    pub fn repeated() -> ([u8; 2], [u8; 2]) {
+        (repeat_hack!(2 => 7), super::repeat_native!(2 => 7))
+    }
+}
+
+fn main() {
+    assert_eq!(repeat_hack!(3 => 1 + 1), [2, 2, 2]);
+    assert_eq!(repeat_native!(3 => 1 + 1), [2, 2, 2]);
+    assert_eq!(within::repeated(), ([7, 7], [7, 7]));
+}
@@ -0,0 +1,14 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(attribute)]
+use test_hack_impl::repeat as repeat_hack;
+
+#[proc_macro_hack(attribute, only_hack_old_rustc)]
+use test_hack_impl::repeat as repeat_native;
+
+fn main() {
+    assert_eq!(repeat_hack!(3 => 1 + 1), [2, 2, 2]);
+    assert_eq!(repeat_native!(3 => 1 + 1), [2, 2, 2]);
+    assert_eq!(repeat_hack!(2 => match 0 { _ => 'x' }), ['x', 'x']);
+    assert_eq!(repeat_native!(2 => match 0 { _ => 'x' }), ['x', 'x']);
+}
@@ -0,0 +1,19 @@
+use proc_macro_hack::proc_macro_hack;
+
+// The implementation crate has no macro `missing`, as if it were behind a
+// feature that is not enabled. Its hidden imports must be left out too.
+#[proc_macro_hack(for missing { #[cfg(any())] })]
+pub use test_hack_impl::{add_one, missing};
+
+#[proc_macro_hack(
+    hygiene = "call_site",
+    for missing_call_site { #[cfg(any())] },
+    for add_x { #[cfg(all())] }
+)]
+pub use test_hack_impl::{add_x, missing_call_site};
+
+fn main() {
+    let x = 1;
+    assert_eq!(add_one!(1), 2);
+    assert_eq!(add_x!(1), 2);
+}
@@ -0,0 +1,12 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+use test_hack_impl::add_one_result;
+
+#[proc_macro_hack(only_hack_old_rustc)]
+use test_hack_impl::add_one_result as add_one_result_native;
+
+fn main() {
+    assert_eq!(add_one_result!(1), 2);
+    assert_eq!(add_one_result_native!(1), 2);
+}
@@ -0,0 +1,13 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(
+    for repeat {
+        #[proc_macro_hack(attribute)]
+    }
+)]
+pub use test_hack_impl::*;
+
+fn main() {
+    assert_eq!(add_one!(1), 2);
+    assert_eq!(repeat!(2 => 1), [1, 1]);
+}
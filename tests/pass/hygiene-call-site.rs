@@ -0,0 +1,9 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(hygiene = "call_site")]
+use test_hack_impl::add_x;
+
+fn main() {
+    let x = 1;
+    assert_eq!(add_x!(2), 3);
+}
@@ -0,0 +1,11 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(items)]
+use test_hack_impl::define_consts;
+
+define_consts!(A = 1, B = 2);
+define_consts!(C = 3);
+
+fn main() {
+    assert_eq!(A + B + C, 6);
+}
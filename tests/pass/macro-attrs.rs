@@ -0,0 +1,30 @@
+use proc_macro_hack::proc_macro_hack;
+
+// Shadowed by the re-export below unless its #[cfg] removes it.
+macro_rules! add_x {
+    ($e:expr) => {
+        $e
+    };
+}
+
+#[proc_macro_hack(
+    for add_one {
+        /// Adds one.
+        #[cfg(all())]
+    },
+    for add_x {
+        #[cfg(any())]
+    },
+    for add_one_str {
+        #[deprecated = "use add_one instead"]
+    },
+)]
+use test_hack_impl::{add_one, add_one_str, add_x};
+
+fn main() {
+    assert_eq!(add_one!(1), 2);
+    assert_eq!(add_x!(2), 2);
+    #[allow(deprecated)]
+    let three = add_one_str!(2);
+    assert_eq!(three, 3);
+}
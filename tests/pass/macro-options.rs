@@ -0,0 +1,21 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(
+    for add_one {
+        #[proc_macro_hack(support_nested)]
+    },
+    for add_x {
+        #[proc_macro_hack(hygiene = "call_site")]
+    },
+    for repeat {
+        #[proc_macro_hack(attribute)]
+    },
+)]
+use test_hack_impl::{add_one, add_x, repeat};
+
+fn main() {
+    let x = 1;
+    assert_eq!(add_one!(add_one!(1)), 3);
+    assert_eq!(add_x!(2), 3);
+    assert_eq!(repeat!(2 => 1), [1, 1]);
+}
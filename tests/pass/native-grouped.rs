@@ -0,0 +1,15 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(
+    only_hack_old_rustc,
+    for add_one {
+        #[proc_macro_hack(always_hack)]
+    }
+)]
+use test_hack_impl::{add_one, add_x};
+
+fn main() {
+    let x = 1;
+    assert_eq!(add_x!(2), 3);
+    assert_eq!(add_one!(2), 3);
+}
@@ -0,0 +1,15 @@
+use proc_macro_hack::proc_macro_hack;
+
+// The `panic!` in the output of add_one_checked! is an ordinary macro call,
+// which needs no internal_macro_calls.
+#[proc_macro_hack(support_nested)]
+use test_hack_impl::add_one_checked;
+
+#[proc_macro_hack(support_nested)]
+use test_hack_impl::add_one;
+
+fn main() {
+    assert_eq!(add_one_checked!(1u8), 2);
+    assert_eq!(add_one_checked!(add_one!(1u8)), 3);
+    assert_eq!(add_one_checked!(add_one!(add_one_checked!(1u8))), 4);
+}
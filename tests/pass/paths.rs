@@ -0,0 +1,54 @@
+use proc_macro_hack::proc_macro_hack;
+
+mod facade {
+    pub mod exprs {
+        pub use test_hack_impl::*;
+    }
+}
+
+mod global {
+    use proc_macro_hack::proc_macro_hack;
+
+    #[proc_macro_hack]
+    use ::test_hack_impl::add_one;
+
+    pub fn two() -> i32 {
+        add_one!(1)
+    }
+}
+
+mod crate_relative {
+    use proc_macro_hack::proc_macro_hack;
+
+    #[proc_macro_hack]
+    use crate::facade::exprs::add_one;
+
+    pub fn two() -> i32 {
+        add_one!(1)
+    }
+}
+
+mod self_relative {
+    use proc_macro_hack::proc_macro_hack;
+
+    mod facade {
+        pub use test_hack_impl::*;
+    }
+
+    #[proc_macro_hack]
+    use self::facade::add_one;
+
+    pub fn two() -> i32 {
+        add_one!(1)
+    }
+}
+
+#[proc_macro_hack]
+pub use crate::facade::exprs::add_one;
+
+fn main() {
+    assert_eq!(add_one!(1), 2);
+    assert_eq!(global::two(), 2);
+    assert_eq!(crate_relative::two(), 2);
+    assert_eq!(self_relative::two(), 2);
+}
@@ -0,0 +1,19 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(pattern)]
+use test_hack_impl::tuple;
+
+tuple!(ONE_AND_ANY = 1, _);
+
+fn main() {
+    tuple!(ZERO_PAIR = 0, 0);
+
+    let classify = |pair: (i32, i32)| match pair {
+        ZERO_PAIR!() => "zeros",
+        ONE_AND_ANY!() => "one",
+        _ => "other",
+    };
+    assert_eq!(classify((0, 0)), "zeros");
+    assert_eq!(classify((1, 5)), "one");
+    assert_eq!(classify((2, 0)), "other");
+}
@@ -0,0 +1,34 @@
+mod facade {
+    pub use test_hack_impl::*;
+}
+
+mod exprs {
+    use proc_macro_hack::proc_macro_hack;
+
+    #[proc_macro_hack]
+    pub(crate) use ::test_hack_impl::add_one;
+
+    pub mod inner {
+        use proc_macro_hack::proc_macro_hack;
+
+        #[proc_macro_hack]
+        pub(in crate::exprs) use crate::facade::add_one as add_one_inner;
+    }
+
+    pub fn three() -> i32 {
+        inner::add_one_inner!(2)
+    }
+}
+
+mod sibling {
+    use crate::exprs::add_one;
+
+    pub fn two() -> i32 {
+        add_one!(1)
+    }
+}
+
+fn main() {
+    assert_eq!(sibling::two(), 2);
+    assert_eq!(exprs::three(), 3);
+}
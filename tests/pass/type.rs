@@ -0,0 +1,15 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(type_position)]
+use test_hack_impl::tuple;
+
+tuple!(Pair = u8, u16);
+
+fn main() {
+    tuple!(Triple = u8, u8, u8);
+
+    let pair: Pair!() = (1, 2);
+    let triple: Triple!() = (1, 2, 3);
+    assert_eq!(pair.1, 2u16);
+    assert_eq!(triple, (1, 2, 3));
+}
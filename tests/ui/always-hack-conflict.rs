@@ -0,0 +1,6 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(only_hack_old_rustc, always_hack)]
+pub use demo::m;
+
+fn main() {}
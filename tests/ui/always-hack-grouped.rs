@@ -0,0 +1,15 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(
+    only_hack_old_rustc,
+    for add_x {
+        #[proc_macro_hack(always_hack)]
+    }
+)]
+use test_hack_impl::{add_one, add_x};
+
+fn main() {
+    let x = 1;
+    let _ = add_one!(x);
+    let _ = add_x!(2);
+}
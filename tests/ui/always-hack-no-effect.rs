@@ -0,0 +1,13 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(always_hack)]
+use test_hack_impl::add_one;
+
+#[proc_macro_hack(
+    for add_x {
+        #[proc_macro_hack(always_hack)]
+    }
+)]
+use test_hack_impl::{add_one_str, add_x};
+
+fn main() {}
@@ -0,0 +1,12 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+use test_hack_impl::repeat;
+
+#[proc_macro_hack(attribute)]
+use test_hack_impl::add_one;
+
+fn main() {
+    let _ = repeat!(3 => 1);
+    let _ = add_one!(3 => 1);
+}
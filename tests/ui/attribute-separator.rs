@@ -0,0 +1,8 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(attribute)]
+use test_hack_impl::repeat;
+
+fn main() {
+    let _ = repeat!(3, 1);
+}
@@ -0,0 +1,10 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(for add_x { #[cfg(any())] })]
+pub use test_hack_impl::{add_one, add_x};
+
+fn main() {
+    let x = 1;
+    let _ = add_one!(x);
+    let _ = add_x!(1);
+}
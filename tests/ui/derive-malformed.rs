@@ -0,0 +1,6 @@
+#[derive(test_hack_impl::_proc_macro_hack_add_one)]
+enum ProcMacroHack {
+    Value = 1,
+}
+
+fn main() {}
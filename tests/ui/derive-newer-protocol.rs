@@ -0,0 +1,7 @@
+#[derive(test_hack_impl::_proc_macro_hack_add_one)]
+#[allow(dead_code)]
+enum ProcMacroHack {
+    Value = (stringify! { 1 }, 99, "9.0.0").1,
+}
+
+fn main() {}
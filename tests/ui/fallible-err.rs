@@ -0,0 +1,16 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+use test_hack_impl::add_one_result;
+
+#[proc_macro_hack(only_hack_old_rustc)]
+use test_hack_impl::add_one_result as add_one_result_native;
+
+#[proc_macro_hack(attribute)]
+use test_hack_impl::repeat;
+
+fn main() {
+    let _ = add_one_result!(1 +);
+    let _ = add_one_result_native!(1 +);
+    let _ = repeat!(x => 1);
+}
@@ -0,0 +1,10 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(
+    for add_two {
+        #[proc_macro_hack(support_nested)]
+    }
+)]
+pub use test_hack_impl::*;
+
+fn main() {}
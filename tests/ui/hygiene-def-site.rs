@@ -0,0 +1,6 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(hygiene = "def_site")]
+pub use demo::m;
+
+fn main() {}
@@ -0,0 +1,9 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+use test_hack_impl::add_x;
+
+fn main() {
+    let x = 1;
+    let _ = add_x!(2);
+}
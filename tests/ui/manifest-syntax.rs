@@ -0,0 +1,5 @@
+proc_macro_hack::manifest!(add_one add_x);
+
+proc_macro_hack::manifest!(add_one, add_x, add_one);
+
+fn main() {}
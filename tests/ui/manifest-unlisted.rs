@@ -0,0 +1,20 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+pub fn listed(input: TokenStream) -> TokenStream {
+    input
+}
+
+#[proc_macro_hack]
+pub fn unlisted_before(input: TokenStream) -> TokenStream {
+    input
+}
+
+proc_macro_hack::manifest!(listed);
+
+#[proc_macro_hack]
+pub fn unlisted_after(input: TokenStream) -> TokenStream {
+    input
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(pattern, support_nested)]
+pub use demo::m;
+
+fn main() {}
@@ -0,0 +1,12 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+use test_hack_impl::add_one_str;
+
+fn main() {
+    // Printed from the input, so the error points at the input's `two`.
+    let _ = add_one_str!(2 * two);
+
+    // Only created by the macro, so the error points at the start of the input.
+    let _ = add_one_str!("two");
+}
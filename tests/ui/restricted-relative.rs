@@ -0,0 +1,9 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+pub(crate) use self::demo::some_macro;
+
+#[proc_macro_hack]
+pub(crate) use test_hack_impl::add_one;
+
+fn main() {}
@@ -0,0 +1,6 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(support_nexted, internal_macro_calls = many, items, itmes, items)]
+pub use demo::some_macro;
+
+fn main() {}
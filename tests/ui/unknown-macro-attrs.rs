@@ -0,0 +1,6 @@
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack(for m3 { #[doc(hidden)] })]
+pub use demo::{m1, m2};
+
+fn main() {}
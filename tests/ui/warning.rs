@@ -0,0 +1,11 @@
+#![deny(deprecated)]
+
+use proc_macro_hack::proc_macro_hack;
+
+#[proc_macro_hack]
+use test_hack_impl::add_one_compat;
+
+fn main() {
+    let _ = add_one_compat!(2);
+    let _ = add_one_compat!(= 2);
+}